@@ -1,3 +1,6 @@
+/// DEFAULT_SOCKET_PATH is the socket used to connect to authd when no other path is configured.
+const DEFAULT_SOCKET_PATH: &str = "/run/authd.sock";
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_server(false)
@@ -10,5 +13,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .define("INTEGRATION_TESTS", "1")
         .compile("db_override");
 
+    println!(
+        "cargo:rustc-env=AUTHD_NSS_DEFAULT_SOCKET={}",
+        DEFAULT_SOCKET_PATH
+    );
+    println!("cargo:rustc-env=AUTHD_NSS_BUILD_INFO={}", build_info());
+
     Ok(())
 }
+
+/// build_info returns a description of the module build: crate version, enabled features and default
+/// socket path.
+fn build_info() -> String {
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(str::to_lowercase))
+        .filter(|f| f != "default")
+        .collect();
+    features.sort();
+
+    format!(
+        "authd-nss version={} features={} default_socket={}",
+        std::env::var("CARGO_PKG_VERSION").unwrap_or_default(),
+        features.join(","),
+        DEFAULT_SOCKET_PATH,
+    )
+}
@@ -5,18 +5,26 @@ import (
 	"context"
 	"fmt"
 	"io"
+	"net"
 	"os"
 	"os/exec"
 	"path/filepath"
 	"runtime"
 	"strings"
+	"sync"
+	"sync/atomic"
 	"syscall"
 	"testing"
 	"time"
 
 	"github.com/stretchr/testify/require"
+	"github.com/ubuntu/authd"
 	cachetests "github.com/ubuntu/authd/internal/cache/tests"
 	"github.com/ubuntu/authd/internal/testutils"
+	"google.golang.org/grpc"
+	"google.golang.org/grpc/codes"
+	"google.golang.org/grpc/metadata"
+	"google.golang.org/grpc/status"
 )
 
 var daemonPath string
@@ -61,7 +69,7 @@ func buildRustNSSLib(t *testing.T) {
 
 // outNSSCommandForLib returns the specific part for the nss command, filtering originOut.
 // It uses the locally build authd nss module for the integration tests.
-func outNSSCommandForLib(t *testing.T, socketPath, originOut string, cmds ...string) (got string, err error) {
+func outNSSCommandForLib(t *testing.T, socketPath, originOut string, env []string, cmds ...string) (got string, err error) {
 	t.Helper()
 
 	// #nosec:G204 - we control the command arguments in tests
@@ -69,6 +77,11 @@ func outNSSCommandForLib(t *testing.T, socketPath, originOut string, cmds ...str
 	cmd.Env = append(cmd.Env, rustCovEnv...)
 	cmd.Env = append(cmd.Env,
 		"AUTHD_NSS_DEBUG=stderr",
+		"AUTHD_NSS_DB_OVERRIDE=1",
+		// The test databases use ids outside of the range generated by authd.
+		"AUTHD_NSS_UID_RANGE=1000-99999",
+		// Most of the tests list all the entries, which is disabled by default.
+		"AUTHD_NSS_ENABLE_ENUMERATION=1",
 		// NSS needs both LD_PRELOAD and LD_LIBRARY_PATH to load the module library
 		fmt.Sprintf("LD_PRELOAD=%s:%s", libPath, os.Getenv("LD_PRELOAD")),
 		fmt.Sprintf("LD_LIBRARY_PATH=%s:%s", filepath.Dir(libPath), os.Getenv("LD_LIBRARY_PATH")),
@@ -78,6 +91,9 @@ func outNSSCommandForLib(t *testing.T, socketPath, originOut string, cmds ...str
 		cmd.Env = append(cmd.Env, fmt.Sprintf("AUTHD_NSS_SOCKET=%s", socketPath))
 	}
 
+	// Appended last, so that tests can override any of the default values above.
+	cmd.Env = append(cmd.Env, env...)
+
 	var out bytes.Buffer
 	cmd.Stdout = io.MultiWriter(os.Stdout, &out)
 	cmd.Stderr = os.Stderr
@@ -133,6 +149,199 @@ paths:
 	return socketPath, stopped
 }
 
+// tempSocketPath returns the path of a socket named name in a new temporary directory, removed when the
+// test ends.
+func tempSocketPath(t *testing.T, name string) string {
+	t.Helper()
+
+	// Socket name has a maximum size, so we can't use t.TempDir() directly.
+	tempDir, err := os.MkdirTemp("", "authd-nss-tests")
+	require.NoError(t, err, "Setup: failed to create socket dir for tests")
+	t.Cleanup(func() { os.RemoveAll(tempDir) })
+
+	return filepath.Join(tempDir, name)
+}
+
+// listenUnix listens on a new unix socket, closed when the test ends, and returns the listener along with
+// the socket path. Nothing answers the connections until the listener is given to a server, like for a
+// hung daemon.
+func listenUnix(t *testing.T) (l net.Listener, socketPath string) {
+	t.Helper()
+
+	socketPath = tempSocketPath(t, "authd.socket")
+	l, err := net.Listen("unix", socketPath)
+	require.NoError(t, err, "Setup: failed to listen on socket")
+	t.Cleanup(func() { l.Close() })
+
+	return l, socketPath
+}
+
+// serveNSS starts a gRPC server answering the NSS requests on l with nss, stopped when the test ends.
+func serveNSS(t *testing.T, l net.Listener, nss authd.NSSServer, opts ...grpc.ServerOption) *grpc.Server {
+	t.Helper()
+
+	s := grpc.NewServer(opts...)
+	authd.RegisterNSSServer(s, nss)
+
+	go func() { _ = s.Serve(l) }()
+	t.Cleanup(s.Stop)
+
+	return s
+}
+
+// runRecordingServer starts a gRPC server on l which doesn't implement any of the NSS methods, but records
+// the user agent of each request it receives.
+func runRecordingServer(t *testing.T, l net.Listener) (userAgents chan string) {
+	t.Helper()
+
+	userAgents = make(chan string, 10)
+	serveNSS(t, l, authd.UnimplementedNSSServer{}, grpc.UnaryInterceptor(func(ctx context.Context, req any, info *grpc.UnaryServerInfo, handler grpc.UnaryHandler) (any, error) {
+		md, _ := metadata.FromIncomingContext(ctx)
+		userAgents <- strings.Join(md.Get("user-agent"), " ")
+		return handler(ctx, req)
+	}))
+
+	return userAgents
+}
+
+// countingListener is a listener keeping track of the number of connections it accepted, and of the maximum
+// number of connections open at the same time.
+type countingListener struct {
+	net.Listener
+
+	mu       sync.Mutex
+	accepted int
+	open     int
+	max      int
+}
+
+// Accept waits for the next connection and counts it as open until it's closed.
+func (l *countingListener) Accept() (net.Conn, error) {
+	conn, err := l.Listener.Accept()
+	if err != nil {
+		return nil, err
+	}
+
+	l.mu.Lock()
+	defer l.mu.Unlock()
+	l.accepted++
+	l.open++
+	l.max = max(l.max, l.open)
+
+	return &countedConn{Conn: conn, l: l}, nil
+}
+
+// Accepted returns the number of connections accepted by the listener.
+func (l *countingListener) Accepted() int {
+	l.mu.Lock()
+	defer l.mu.Unlock()
+	return l.accepted
+}
+
+// MaxOpen returns the maximum number of connections that were open at the same time.
+func (l *countingListener) MaxOpen() int {
+	l.mu.Lock()
+	defer l.mu.Unlock()
+	return l.max
+}
+
+// countedConn is a connection accepted by a countingListener.
+type countedConn struct {
+	net.Conn
+
+	l         *countingListener
+	closeOnce sync.Once
+}
+
+// Close closes the connection and stops counting it as open.
+func (c *countedConn) Close() error {
+	c.closeOnce.Do(func() {
+		c.l.mu.Lock()
+		defer c.l.mu.Unlock()
+		c.l.open--
+	})
+	return c.Conn.Close()
+}
+
+// runCountingServer starts a gRPC server on l which doesn't implement any of the NSS methods, but records
+// the maximum number of connections open at the same time, returned by maxConns.
+func runCountingServer(t *testing.T, l net.Listener) (maxConns func() int) {
+	t.Helper()
+
+	cl := &countingListener{Listener: l}
+	serveNSS(t, cl, authd.UnimplementedNSSServer{})
+
+	return cl.MaxOpen
+}
+
+// statusServer is a NSS server answering the passwd requests by name with a sequence of status codes.
+type statusServer struct {
+	authd.UnimplementedNSSServer
+
+	codes    []codes.Code
+	requests atomic.Int32
+}
+
+// GetPasswdByName counts the request and answers with the next status code of the sequence, the last one
+// being repeated once the sequence is exhausted. When the code is OK, the requested user is returned.
+func (s *statusServer) GetPasswdByName(_ context.Context, req *authd.GetByNameRequest) (*authd.PasswdEntry, error) {
+	n := int(s.requests.Add(1))
+	code := s.codes[min(n, len(s.codes))-1]
+	if code != codes.OK {
+		return nil, status.Error(code, "")
+	}
+
+	return &authd.PasswdEntry{
+		Name:    req.GetName(),
+		Passwd:  "x",
+		Uid:     1111,
+		Gid:     11111,
+		Homedir: filepath.Join("/home", req.GetName()),
+		Shell:   "/bin/bash",
+	}, nil
+}
+
+// Requests returns the number of requests received by the server.
+func (s *statusServer) Requests() int {
+	return int(s.requests.Load())
+}
+
+// runStatusServer starts a gRPC server on l answering the passwd requests by name with the given sequence
+// of status codes, and returns the function returning the number of requests it received.
+func runStatusServer(t *testing.T, l net.Listener, statusCodes ...codes.Code) (requests func() int) {
+	t.Helper()
+
+	nss := &statusServer{codes: statusCodes}
+	serveNSS(t, l, nss)
+
+	return nss.Requests
+}
+
+// groupsServer is a NSS server listing a fixed set of groups.
+type groupsServer struct {
+	authd.UnimplementedNSSServer
+
+	groups   []*authd.GroupEntry
+	requests atomic.Int32
+}
+
+// GetGroupEntries counts the request and returns the groups of the server.
+func (s *groupsServer) GetGroupEntries(context.Context, *authd.Empty) (*authd.GroupEntries, error) {
+	s.requests.Add(1)
+	return &authd.GroupEntries{Entries: s.groups}, nil
+}
+
+// runGroupsServer starts a gRPC server on l listing the given groups, and returns the function returning
+// the number of requests it received.
+func runGroupsServer(t *testing.T, l net.Listener, groups ...*authd.GroupEntry) (requests func() int) {
+	t.Helper()
+
+	nss := &groupsServer{groups: groups}
+	serveNSS(t, l, nss)
+
+	return func() int { return int(nss.requests.Load()) }
+}
+
 // buildDaemon builds the daemon executable and returns the binary path.
 func buildDaemon() (execPath string, cleanup func(), err error) {
 	projectRoot := getProjectRoot()
@@ -2,15 +2,27 @@ package nss_test
 
 import (
 	"context"
+	"debug/elf"
+	"encoding/json"
 	"flag"
 	"fmt"
 	"log"
+	"net"
 	"os"
 	"os/exec"
+	"path/filepath"
+	"regexp"
+	"sort"
+	"strconv"
+	"strings"
+	"syscall"
 	"testing"
+	"time"
 
 	"github.com/stretchr/testify/require"
+	"github.com/ubuntu/authd"
 	"github.com/ubuntu/authd/internal/testutils"
+	"google.golang.org/grpc/codes"
 )
 
 var libPath string
@@ -33,10 +45,14 @@ func TestIntegration(t *testing.T) {
 		db      string
 		key     string
 		cacheDB string
+		env     []string
 
-		noDaemon       bool
-		noCustomSocket bool
-		wantSecondCall bool
+		noDaemon         bool
+		noCustomSocket   bool
+		hungDaemon       bool
+		noDBOverride     bool
+		socketAsFallback bool
+		wantSecondCall   bool
 
 		wantErr bool
 	}{
@@ -48,6 +64,20 @@ func TestIntegration(t *testing.T) {
 		"Get entry from group by name":  {db: "group", key: "group1"},
 		"Get entry from shadow by name": {db: "shadow", key: "user1"},
 
+		"Get groups of user with initgroups":            {db: "initgroups", key: "user2"},
+		"Get no groups of unknown user with initgroups": {db: "initgroups", key: "doesnotexist"},
+		"Get no groups of denied user with initgroups":  {db: "initgroups", key: "user2", env: []string{"AUTHD_NSS_DENY_USERS=user2"}},
+
+		"Get entry from passwd by name in upn format":                      {db: "passwd", key: "user5@EXAMPLE", cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=upn"}},
+		"Get entry from passwd by name in underscore format":               {db: "passwd", key: "EXAMPLE_user5", cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=underscore"}},
+		"Get entry from passwd by id in upn format":                        {db: "passwd", key: "5555", cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=upn"}},
+		"Get entry from group by name in upn format":                       {db: "group", key: "group5@EXAMPLE", cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=upn"}},
+		"Get all entries from passwd in upn format":                        {db: "passwd", cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=upn"}},
+		"Get entry from passwd by plain name in upn format":                {db: "passwd", key: "user1", cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=upn"}},
+		"Get entry from passwd by plain name in underscore format":         {db: "passwd", key: "user1", cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=underscore"}},
+		"Get entry from passwd by domain name when name format is not set": {db: "passwd", key: `EXAMPLE\user5`, cacheDB: "user_with_domain_name"},
+		"Get entry from passwd by domain name in upn format":               {db: "passwd", key: `EXAMPLE\user5`, cacheDB: "user_with_domain_name", env: []string{"AUTHD_NSS_NAME_FORMAT=upn"}},
+
 		"Get entry from passwd by id": {db: "passwd", key: "1111"},
 		"Get entry from group by id":  {db: "group", key: "11111"},
 
@@ -75,6 +105,62 @@ func TestIntegration(t *testing.T) {
 
 		"Error when getting passwd by id and daemon is not available": {db: "passwd", key: "1111", noDaemon: true, wantErr: true},
 		"Error when getting group by id and daemon is not available":  {db: "group", key: "11111", noDaemon: true, wantErr: true},
+
+		"Error when getting passwd by name and daemon is not answering":                  {db: "passwd", key: "user1", noDaemon: true, hungDaemon: true, wantErr: true},
+		"Returns empty when getting all entries from passwd and daemon is not answering": {db: "passwd", noDaemon: true, hungDaemon: true},
+
+		"Returns empty when getting all entries from passwd and enumeration is disabled": {db: "passwd", env: []string{"AUTHD_NSS_ENABLE_ENUMERATION=0"}},
+		"Returns empty when getting all entries from group and enumeration is disabled":  {db: "group", env: []string{"AUTHD_NSS_ENABLE_ENUMERATION=0"}},
+		"Returns empty when getting all entries from shadow and enumeration is disabled": {db: "shadow", env: []string{"AUTHD_NSS_ENABLE_ENUMERATION=0"}},
+		"Get entry from passwd by name when enumeration is disabled":                     {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_ENABLE_ENUMERATION=0"}},
+
+		"Get the first entries from passwd when enumeration is capped":    {db: "passwd", env: []string{"AUTHD_NSS_MAX_ENUM_ENTRIES=2"}},
+		"Get the first entries from group when enumeration is capped":     {db: "group", env: []string{"AUTHD_NSS_MAX_ENUM_ENTRIES=2"}},
+		"Get the first entries from shadow when enumeration is capped":    {db: "shadow", env: []string{"AUTHD_NSS_MAX_ENUM_ENTRIES=2"}},
+		"Get all entries from passwd when enumeration cap is not reached": {db: "passwd", env: []string{"AUTHD_NSS_MAX_ENUM_ENTRIES=3"}},
+
+		"Returns empty when getting all entries from passwd and response exceeds the size limit": {db: "passwd", env: []string{"AUTHD_NSS_MAX_RESPONSE_SIZE=128"}},
+		"Get entry from passwd by name when response is within the size limit":                   {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_MAX_RESPONSE_SIZE=128"}},
+		"Get all entries from passwd when response size limit is invalid":                        {db: "passwd", env: []string{"AUTHD_NSS_MAX_RESPONSE_SIZE=-1"}},
+
+		"Error when getting passwd by name and database override is not enabled": {db: "passwd", key: "user1", noDBOverride: true, wantErr: true},
+
+		"Get entry from passwd by name when another user is denied": {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_DENY_USERS=root,user2"}},
+		"Get entry from passwd by id when uid is allowed":           {db: "passwd", key: "1111", env: []string{"AUTHD_NSS_ALLOW_UIDS=1000-1999"}},
+
+		"Error when getting passwd by name and user is denied":      {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_DENY_USERS=root,user1"}, wantErr: true},
+		"Error when getting passwd by name and user is not allowed": {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_ALLOW_USERS=user2"}, wantErr: true},
+		"Error when getting passwd by id and uid is denied":         {db: "passwd", key: "1111", env: []string{"AUTHD_NSS_DENY_UIDS=0,1000-1999"}, wantErr: true},
+		"Error when getting passwd by name and uid is denied":       {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_DENY_UIDS=1111"}, wantErr: true},
+		"Error when getting shadow by name and user is denied":      {db: "shadow", key: "user1", env: []string{"AUTHD_NSS_DENY_USERS=user1"}, wantErr: true},
+
+		"Get entry from passwd by name when ids are in range":       {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_UID_RANGE=1111-11111"}},
+		"Get all entries from passwd skipping entries with uid 0":   {db: "passwd", cacheDB: "user_and_group_with_id_0"},
+		"Get all entries from group skipping entries with gid 0":    {db: "group", cacheDB: "user_and_group_with_id_0"},
+		"Error when getting passwd by name and uid is 0":            {db: "passwd", key: "user1", cacheDB: "user_and_group_with_id_0", wantErr: true},
+		"Error when getting group by name and gid is 0":             {db: "group", key: "group1", cacheDB: "user_and_group_with_id_0", wantErr: true},
+		"Error when getting passwd by name and uid is out of range": {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_UID_RANGE=2000-99999"}, wantErr: true},
+		"Error when getting group by id and gid is out of range":    {db: "group", key: "11111", env: []string{"AUTHD_NSS_UID_RANGE=20000-99999"}, wantErr: true},
+
+		"Get synthesized primary group by id":                                 {db: "group", key: "44444", cacheDB: "user_without_primary_group", env: []string{"AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS=1"}},
+		"Get synthesized primary group by name with a template":               {db: "group", key: "user4-group", cacheDB: "user_without_primary_group", env: []string{"AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS=1", "AUTHD_NSS_PRIMARY_GROUP_NAME={user}-group"}},
+		"Get existing group by id when synthesizing primary groups":           {db: "group", key: "11111", cacheDB: "user_without_primary_group", env: []string{"AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS=1"}},
+		"Error when getting group by id and gid is not a primary group":       {db: "group", key: "404", cacheDB: "user_without_primary_group", env: []string{"AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS=1"}, wantErr: true},
+		"Error when getting primary group by id and synthesis is not enabled": {db: "group", key: "44444", cacheDB: "user_without_primary_group", wantErr: true},
+		"Error when getting synthesized primary group of a denied user":       {db: "group", key: "44444", cacheDB: "user_without_primary_group", env: []string{"AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS=1", "AUTHD_NSS_DENY_USERS=user4"}, wantErr: true},
+		"Error when getting synthesized primary group of a denied uid":        {db: "group", key: "44444", cacheDB: "user_without_primary_group", env: []string{"AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS=1", "AUTHD_NSS_DENY_UIDS=4444"}, wantErr: true},
+
+		"Get entry from passwd by name using the fallback socket":     {db: "passwd", key: "user1", socketAsFallback: true},
+		"Error when getting passwd by name and neither socket exists": {db: "passwd", key: "user1", noDaemon: true, socketAsFallback: true, wantErr: true},
+
+		"Get entry from passwd by id next to an entry with an empty name": {db: "passwd", key: "2222", cacheDB: "user_and_group_with_empty_name"},
+		"Error when getting passwd by id and name is empty":               {db: "passwd", key: "1111", cacheDB: "user_and_group_with_empty_name", wantErr: true},
+		"Error when getting group by id and name is empty":                {db: "group", key: "11111", cacheDB: "user_and_group_with_empty_name", wantErr: true},
+
+		"Get entry from passwd by name with overridden shell":        {db: "passwd", key: "user1", env: []string{"AUTHD_NSS_OVERRIDES_FILE=testdata/overrides.conf"}},
+		"Get entry from passwd by id with overridden shell and home": {db: "passwd", key: "2222", env: []string{"AUTHD_NSS_OVERRIDES_FILE=testdata/overrides.conf"}},
+		"Get entry from passwd by name not matching any override":    {db: "passwd", key: "user3", env: []string{"AUTHD_NSS_OVERRIDES_FILE=testdata/overrides.conf"}},
+		"Get all entries from passwd with overrides":                 {db: "passwd", env: []string{"AUTHD_NSS_OVERRIDES_FILE=testdata/overrides.conf"}},
 	}
 	for name, tc := range tests {
 		tc := tc
@@ -95,13 +181,31 @@ func TestIntegration(t *testing.T) {
 					<-daemonStopped
 				})
 			}
+			if tc.hungDaemon {
+				_, socketPath = listenUnix(t)
+			}
 
 			cmds := []string{"getent", tc.db}
 			if tc.key != "" {
 				cmds = append(cmds, tc.key)
 			}
 
-			got, err := outNSSCommandForLib(t, socketPath, originOuts[tc.db], cmds...)
+			env := append([]string{}, tc.env...)
+			if tc.noDBOverride {
+				env = append(env, "AUTHD_NSS_DB_OVERRIDE=0")
+			}
+			if tc.socketAsFallback {
+				fallback := socketPath
+				if fallback == "" {
+					fallback = filepath.Join(t.TempDir(), "fallback.sock")
+				}
+				env = append(env,
+					fmt.Sprintf("AUTHD_NSS_SOCKET=%s", filepath.Join(t.TempDir(), "missing.sock")),
+					fmt.Sprintf("AUTHD_NSS_FALLBACK_SOCKETS=%s", fallback),
+				)
+			}
+
+			got, err := outNSSCommandForLib(t, socketPath, originOuts[tc.db], env, cmds...)
 			if tc.wantErr {
 				require.Error(t, err, "Expected an error, but got none")
 				return
@@ -113,7 +217,7 @@ func TestIntegration(t *testing.T) {
 
 			// This is to check that some cache tasks, such as cleaning a corrupted database, work as expected.
 			if tc.wantSecondCall {
-				got, err := outNSSCommandForLib(t, socketPath, originOuts[tc.db], cmds...)
+				got, err := outNSSCommandForLib(t, socketPath, originOuts[tc.db], env, cmds...)
 				require.NoError(t, err, "Expected no error, but got %v", err)
 				require.Empty(t, got, "Expected empty output, but got %q", got)
 			}
@@ -121,6 +225,1183 @@ func TestIntegration(t *testing.T) {
 	}
 }
 
+func TestFallback(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	//#nosec:G204 - We control the cmd arguments in tests.
+	data, err := exec.Command("getent", "passwd").CombinedOutput()
+	require.NoError(t, err, "Setup: can't run getent to get original output from system")
+	originOut := string(data)
+
+	storedEntry := "user1\x00x\x001111\x0011111\x00User1\x00/home/user1\x00/bin/bash"
+
+	tests := map[string]struct {
+		key string
+		env []string
+
+		storedAge  time.Duration
+		noMaxAge   bool
+		withDaemon bool
+
+		wantStored bool
+		wantErr    bool
+	}{
+		"Get entry from passwd by name when daemon is not available": {key: "user1"},
+		"Get entry from passwd by id when daemon is not available":   {key: "1111"},
+		"Store entry when getting passwd by name":                    {key: "user1", withDaemon: true, wantStored: true},
+
+		"Error when stored entry is expired":              {key: "user1", storedAge: time.Hour, wantErr: true},
+		"Error when fallback is not enabled":              {key: "user1", noMaxAge: true, wantErr: true},
+		"Error when entry was never stored":               {key: "user2", wantErr: true},
+		"Error when stored entry found by id is denied":   {key: "1111", env: []string{"AUTHD_NSS_DENY_USERS=user1"}, wantErr: true},
+		"Error when stored entry found by name is denied": {key: "user1", env: []string{"AUTHD_NSS_DENY_UIDS=1111"}, wantErr: true},
+		"Error when stored entry is outside of uid range": {key: "user1", env: []string{"AUTHD_NSS_UID_RANGE=2000-3000"}, wantErr: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			fallbackDir := t.TempDir()
+			if !tc.withDaemon {
+				for _, f := range []string{"passwd-name-user1", "passwd-uid-1111"} {
+					p := filepath.Join(fallbackDir, f)
+					require.NoError(t, os.WriteFile(p, []byte(storedEntry), 0600), "Setup: failed to write stored entry")
+					mtime := time.Now().Add(-tc.storedAge)
+					require.NoError(t, os.Chtimes(p, mtime, mtime), "Setup: failed to age stored entry")
+				}
+			}
+
+			env := append([]string{fmt.Sprintf("AUTHD_NSS_FALLBACK_DIR=%s", fallbackDir)}, tc.env...)
+			if !tc.noMaxAge {
+				env = append(env, "AUTHD_NSS_FALLBACK_MAX_AGE=60")
+			}
+
+			var socketPath string
+			if tc.withDaemon {
+				ctx, cancel := context.WithCancel(context.Background())
+				var daemonStopped chan struct{}
+				socketPath, daemonStopped = runDaemon(ctx, t, "multiple_users_and_groups")
+				t.Cleanup(func() {
+					cancel()
+					<-daemonStopped
+				})
+			}
+
+			got, err := outNSSCommandForLib(t, socketPath, originOut, env, "getent", "passwd", tc.key)
+			if tc.wantErr {
+				require.Error(t, err, "Expected an error, but got none")
+				return
+			}
+			require.NoError(t, err, "Expected no error, but got %v", err)
+
+			want := testutils.LoadWithUpdateFromGolden(t, got)
+			require.Equal(t, want, got, "Outputs must match")
+
+			if tc.wantStored {
+				require.FileExists(t, filepath.Join(fallbackDir, "passwd-name-user1"), "Entry should be stored by name")
+				require.FileExists(t, filepath.Join(fallbackDir, "passwd-uid-1111"), "Entry should be stored by id")
+			}
+		})
+	}
+}
+
+func TestSnapshot(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	originOuts := map[string]string{}
+	for _, db := range []string{"passwd", "group", "shadow"} {
+		//#nosec:G204 - We control the cmd arguments in tests.
+		data, err := exec.Command("getent", db).CombinedOutput()
+		require.NoError(t, err, "Setup: can't run getent to get original output from system")
+		originOuts[db] = string(data)
+	}
+
+	tests := map[string]struct {
+		db           string
+		key          string
+		snapshot     string
+		snapshotMode os.FileMode
+		env          []string
+		withDaemon   bool
+
+		wantErr bool
+	}{
+		"Get all entries from passwd":   {db: "passwd"},
+		"Get all entries from group":    {db: "group"},
+		"Get entry from passwd by name": {db: "passwd", key: "user1"},
+		"Get entry from passwd by id":   {db: "passwd", key: "2222"},
+		"Get entry from group by name":  {db: "group", key: "commongroup"},
+		"Get entry from group by id":    {db: "group", key: "11111"},
+		"Get entry from shadow by name": {db: "shadow", key: "user1"},
+
+		"Get groups of user with initgroups":            {db: "initgroups", key: "user2"},
+		"Get no groups of unknown user with initgroups": {db: "initgroups", key: "doesnotexist"},
+		"Get no groups of denied user with initgroups":  {db: "initgroups", key: "user2", env: []string{"AUTHD_NSS_DENY_USERS=user2"}},
+
+		"Error when getting passwd by name and entry is not in snapshot": {db: "passwd", key: "user3", wantErr: true},
+		"Error when getting group by id and entry is not in snapshot":    {db: "group", key: "33333", wantErr: true},
+		"Error when snapshot does not exist":                             {db: "passwd", key: "user1", snapshot: "doesnotexist", wantErr: true},
+		"Error when snapshot version is not supported":                   {db: "passwd", key: "user1", snapshot: "unsupported_version", wantErr: true},
+		"Error when snapshot is writable by others":                      {db: "passwd", key: "user1", snapshotMode: 0666, wantErr: true},
+		"Error when entry is only known by the running daemon":           {db: "passwd", key: "user3", withDaemon: true, wantErr: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			if tc.snapshot == "" {
+				tc.snapshot = "valid"
+			}
+			if tc.snapshotMode == 0 {
+				tc.snapshotMode = 0600
+			}
+			// The snapshot is only used if it can't be modified by others, whatever the mode of the checkout.
+			snapshotPath := filepath.Join(t.TempDir(), tc.snapshot+".snapshot")
+			if data, err := os.ReadFile(filepath.Join("testdata", "snapshots", tc.snapshot+".snapshot")); err == nil {
+				require.NoError(t, os.WriteFile(snapshotPath, data, tc.snapshotMode), "Setup: failed to copy snapshot")
+				require.NoError(t, os.Chmod(snapshotPath, tc.snapshotMode), "Setup: failed to change snapshot mode")
+			}
+
+			// The snapshot is the only source of entries, so authd is never contacted, even if it's running.
+			var socketPath string
+			if tc.withDaemon {
+				ctx, cancel := context.WithCancel(context.Background())
+				var daemonStopped chan struct{}
+				socketPath, daemonStopped = runDaemon(ctx, t, "multiple_users_and_groups")
+				t.Cleanup(func() {
+					cancel()
+					<-daemonStopped
+				})
+			}
+
+			cmds := []string{"getent", tc.db}
+			if tc.key != "" {
+				cmds = append(cmds, tc.key)
+			}
+
+			env := append([]string{fmt.Sprintf("AUTHD_NSS_SNAPSHOT=%s", snapshotPath)}, tc.env...)
+			got, err := outNSSCommandForLib(t, socketPath, originOuts[tc.db], env, cmds...)
+			if tc.wantErr {
+				require.Error(t, err, "Expected an error, but got none")
+				return
+			}
+			require.NoError(t, err, "Expected no error, but got %v", err)
+
+			want := testutils.LoadWithUpdateFromGolden(t, got)
+			require.Equal(t, want, got, "Outputs must match")
+		})
+	}
+}
+
+func TestBuildInfo(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	manifest, err := os.ReadFile(filepath.Join(getProjectRoot(), "nss", "Cargo.toml"))
+	require.NoError(t, err, "Setup: failed to read crate manifest")
+	version := regexp.MustCompile(`(?m)^version = "(.*)"$`).FindSubmatch(manifest)
+	require.NotNil(t, version, "Setup: failed to find crate version in manifest")
+
+	lib, err := os.ReadFile(libPath)
+	require.NoError(t, err, "Setup: failed to read NSS library")
+
+	require.Contains(t, string(lib), fmt.Sprintf("authd-nss version=%s ", version[1]), "Build info should contain the crate version")
+}
+
+func TestBuildWithoutLogging(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	cargo := os.Getenv("CARGO_PATH")
+	if cargo == "" {
+		cargo = "cargo"
+	}
+	target := t.TempDir()
+
+	// #nosec:G204 - we control the command arguments in tests
+	cmd := exec.Command(cargo, "build", "--no-default-features", "--target-dir", target)
+	cmd.Dir = getProjectRoot()
+	out, err := cmd.CombinedOutput()
+	require.NoError(t, err, "Crate should build without the logging feature: %s", out)
+
+	withLogging := nssSymbols(t, libPath)
+	withoutLogging := nssSymbols(t, filepath.Join(target, os.Getenv("DEB_HOST_RUST_TYPE"), "debug", "libnss_authd.so"))
+	require.NotEmpty(t, withLogging, "Setup: library should export NSS symbols")
+	require.Equal(t, withLogging, withoutLogging, "Library built without logging should export the same NSS symbols")
+}
+
+func TestUserAgent(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	manifest, err := os.ReadFile(filepath.Join(getProjectRoot(), "nss", "Cargo.toml"))
+	require.NoError(t, err, "Setup: failed to read crate manifest")
+	version := regexp.MustCompile(`(?m)^version = "(.*)"$`).FindSubmatch(manifest)
+	require.NotNil(t, version, "Setup: failed to find crate version in manifest")
+
+	l, socketPath := listenUnix(t)
+	userAgents := runRecordingServer(t, l)
+
+	// The server doesn't know any user, so the lookup itself fails.
+	_, err = outNSSCommandForLib(t, socketPath, "", nil, "getent", "passwd", "user1")
+	require.Error(t, err, "Lookup should fail with a server not implementing the NSS service")
+
+	select {
+	case got := <-userAgents:
+		require.True(t, strings.HasPrefix(got, fmt.Sprintf("authd-nss/%s", version[1])), "User agent should identify the NSS module, got %q", got)
+	default:
+		require.Fail(t, "Server should have received a request")
+	}
+}
+
+func TestConnectLimit(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	lookupsPath := buildTestProgram(t, "concurrent_lookups", "-lpthread")
+	l, socketPath := listenUnix(t)
+	maxConns := runCountingServer(t, l)
+
+	env := []string{"AUTHD_NSS_MAX_CONCURRENT_CONNECTS=1"}
+	_, err := outNSSCommandForLib(t, socketPath, "", env, lookupsPath, "user1", "8")
+	require.NoError(t, err, "Concurrent lookups program should not fail")
+
+	got := maxConns()
+	require.NotZero(t, got, "Server should have received a connection")
+	require.LessOrEqual(t, got, 1, "Connections should not exceed the limit")
+}
+
+func TestNegativeCache(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		env []string
+
+		wantRequests int
+	}{
+		"Ask authd once for a missing user looked up twice": {env: []string{"AUTHD_NSS_NEGATIVE_CACHE_TTL=60"}, wantRequests: 1},
+
+		"Ask authd each time for a missing user when negative cache is disabled": {wantRequests: 2},
+		"Ask authd each time for a missing user when negative cache TTL is 0":    {env: []string{"AUTHD_NSS_NEGATIVE_CACHE_TTL=0"}, wantRequests: 2},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			l, socketPath := listenUnix(t)
+			requests := runStatusServer(t, l, codes.NotFound)
+
+			// getent looks up each of the keys in the same process.
+			_, err := outNSSCommandForLib(t, socketPath, "", tc.env, "getent", "passwd", "doesnotexist", "doesnotexist")
+			require.Error(t, err, "Lookup of a missing user should fail")
+
+			require.Equal(t, tc.wantRequests, requests(), "Unexpected number of requests received by authd")
+		})
+	}
+}
+
+func TestCache(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	lookupsPath := buildTestProgram(t, "cached_lookups", "-ldl")
+
+	tests := map[string]struct {
+		env     []string
+		lookups []string
+
+		wantRequests int
+		wantHits     int
+		wantMisses   int
+	}{
+		"Ask authd once for a user looked up twice":         {env: []string{"AUTHD_NSS_PASSWD_CACHE_TTL=60"}, lookups: []string{"user1", "user1"}, wantRequests: 1, wantHits: 1, wantMisses: 1},
+		"Ask authd again for a user once its entry expired": {env: []string{"AUTHD_NSS_PASSWD_CACHE_TTL=1"}, lookups: []string{"user1", "+2", "user1"}, wantRequests: 2, wantMisses: 2},
+
+		// user2 is evicted to make room for user3, as user1 was used more recently.
+		"Evict the least recently used entry when the cache is full": {env: []string{"AUTHD_NSS_PASSWD_CACHE_TTL=60", "AUTHD_NSS_PASSWD_CACHE_SIZE=2"}, lookups: []string{"user1", "user2", "user1", "user3", "user1", "user2"}, wantRequests: 4, wantHits: 2, wantMisses: 4},
+
+		"Use the default cache size when it is invalid": {env: []string{"AUTHD_NSS_PASSWD_CACHE_TTL=60", "AUTHD_NSS_PASSWD_CACHE_SIZE=invalid"}, lookups: []string{"user1", "user2", "user3", "user1", "user2", "user3"}, wantRequests: 3, wantHits: 3, wantMisses: 3},
+
+		"Ask authd each time for a user when cache is disabled":               {lookups: []string{"user1", "user1"}, wantRequests: 2},
+		"Ask authd each time for a user when cache TTL is 0":                  {env: []string{"AUTHD_NSS_PASSWD_CACHE_TTL=0"}, lookups: []string{"user1", "user1"}, wantRequests: 2},
+		"Ask authd each time for a user when only the group cache is enabled": {env: []string{"AUTHD_NSS_GROUP_CACHE_TTL=60"}, lookups: []string{"user1", "user1"}, wantRequests: 2},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			l, socketPath := listenUnix(t)
+			requests := runStatusServer(t, l, codes.OK)
+
+			got, err := outNSSCommandForLib(t, socketPath, "", tc.env, append([]string{lookupsPath}, tc.lookups...)...)
+			require.NoError(t, err, "Cached lookups program should not fail")
+
+			require.Equal(t, tc.wantRequests, requests(), "Unexpected number of requests received by authd")
+
+			m := regexp.MustCompile(`cache_passwd=hits:(\d+),misses:(\d+)`).FindStringSubmatch(got)
+			if tc.wantHits == 0 && tc.wantMisses == 0 {
+				require.Nil(t, m, "Metrics should not report the usage of a disabled cache")
+				return
+			}
+			require.NotNil(t, m, "Metrics should report the usage of the passwd cache")
+			require.Equal(t, strconv.Itoa(tc.wantHits), m[1], "Unexpected number of cache hits")
+			require.Equal(t, strconv.Itoa(tc.wantMisses), m[2], "Unexpected number of cache misses")
+		})
+	}
+}
+
+func TestCacheInvalidation(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	lookupsPath := buildTestProgram(t, "cached_lookups", "-ldl")
+
+	sentinel := filepath.Join(t.TempDir(), "authd.restarting")
+	err := os.WriteFile(sentinel, nil, 0600)
+	require.NoError(t, err, "Setup: failed to create restart sentinel")
+
+	l, socketPath := listenUnix(t)
+	requests := runStatusServer(t, l, codes.OK)
+
+	env := []string{"AUTHD_NSS_PASSWD_CACHE_TTL=60", "AUTHD_NSS_RESTART_SENTINEL=" + sentinel}
+	_, err = outNSSCommandForLib(t, socketPath, "", env, lookupsPath, "user1", "user1")
+	require.NoError(t, err, "Cached lookups program should not fail")
+
+	require.Equal(t, 2, requests(), "Lookups should reach authd while it is restarting")
+}
+
+func TestFallbackWithCache(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	lookupsPath := buildTestProgram(t, "cached_lookups", "-ldl")
+
+	l, socketPath := listenUnix(t)
+	requests := runStatusServer(t, l, codes.OK)
+	fallbackDir := t.TempDir()
+
+	env := []string{"AUTHD_NSS_PASSWD_CACHE_TTL=60", "AUTHD_NSS_FALLBACK_MAX_AGE=60", "AUTHD_NSS_FALLBACK_DIR=" + fallbackDir}
+	start := time.Now()
+	_, err := outNSSCommandForLib(t, socketPath, "", env, lookupsPath, "user1", "+3", "user1")
+	require.NoError(t, err, "Cached lookups program should not fail")
+	require.Equal(t, 1, requests(), "Second lookup should be answered from the cache")
+
+	// The second lookup happens 3 seconds after the first one, so a stored entry written by it would be more recent.
+	fi, err := os.Stat(filepath.Join(fallbackDir, "passwd-name-user1"))
+	require.NoError(t, err, "Entry should be stored when authd answers")
+	require.True(t, fi.ModTime().Before(start.Add(2*time.Second)), "Entry should not be stored again when answered from the cache")
+}
+
+func TestInterruptedLookups(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	lookupsPath := buildTestProgram(t, "interrupted_lookups")
+
+	l, socketPath := listenUnix(t)
+	requests := runStatusServer(t, l, codes.OK)
+
+	// Each lookup connects to authd while its process keeps being interrupted by signals.
+	got, err := outNSSCommandForLib(t, socketPath, "", nil, lookupsPath, "user1", "20")
+	require.NoError(t, err, "Lookups should not fail when interrupted by signals: %s", got)
+
+	require.Equal(t, 20, requests(), "Each lookup should reach authd")
+}
+
+func TestChannelReuse(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	lookupsPath := buildTestProgram(t, "cached_lookups", "-ldl")
+
+	l, socketPath := listenUnix(t)
+	cl := &countingListener{Listener: l}
+	requests := runStatusServer(t, cl, codes.OK)
+
+	_, err := outNSSCommandForLib(t, socketPath, "", nil, lookupsPath, "user1", "user2", "user3")
+	require.NoError(t, err, "Lookups program should not fail")
+
+	require.Equal(t, 3, requests(), "Each lookup should reach authd")
+	require.Equal(t, 1, cl.Accepted(), "Lookups should share the same connection")
+}
+
+func TestReconnectAfterRestart(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	lookupsPath := buildTestProgram(t, "cached_lookups", "-ldl")
+
+	l, socketPath := listenUnix(t)
+	nss := &statusServer{codes: []codes.Code{codes.OK}}
+	s := serveNSS(t, l, nss)
+
+	done := make(chan error)
+	go func() {
+		_, err := outNSSCommandForLib(t, socketPath, "", nil, lookupsPath, "user1", "+3", "user1")
+		done <- err
+	}()
+
+	// Restart authd while the program sleeps between its lookups.
+	require.Eventually(t, func() bool { return nss.Requests() == 1 }, 5*time.Second, 10*time.Millisecond, "First lookup should reach authd")
+	s.Stop()
+	newL, err := net.Listen("unix", socketPath)
+	require.NoError(t, err, "Setup: failed to listen again on socket")
+	t.Cleanup(func() { newL.Close() })
+	cl := &countingListener{Listener: newL}
+	newRequests := runStatusServer(t, cl, codes.OK)
+
+	require.NoError(t, <-done, "Lookups program should not fail")
+
+	require.Equal(t, 1, nss.Requests(), "Second lookup should not reach the stopped daemon")
+	require.Equal(t, 1, newRequests(), "Second lookup should reach the restarted daemon")
+	require.Equal(t, 1, cl.Accepted(), "Second lookup should reconnect to the restarted daemon")
+}
+
+func TestRequestTimeout(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	_, socketPath := listenUnix(t)
+
+	// The default request timeout of the integration tests is 10 seconds.
+	start := time.Now()
+	_, err := outNSSCommandForLib(t, socketPath, "", []string{"AUTHD_NSS_REQUEST_TIMEOUT_MS=500"}, "getent", "passwd", "user1")
+	require.Error(t, err, "Lookup should fail when the daemon is not answering")
+	require.Less(t, time.Since(start), 5*time.Second, "Lookup should give up after the configured request timeout")
+}
+
+func TestConnectionFailureLogs(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		closeConnections bool
+
+		wantFields []string
+	}{
+		"Log errno when nothing listens on the socket":       {wantFields: []string{fmt.Sprintf("errno=%d", syscall.ECONNREFUSED), "peer_pid=-"}},
+		"Log peer pid when the daemon closes the connection": {closeConnections: true, wantFields: []string{fmt.Sprintf("peer_pid=%d", os.Getpid())}},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			l, socketPath := listenUnix(t)
+
+			if tc.closeConnections {
+				go func() {
+					for {
+						conn, err := l.Accept()
+						if err != nil {
+							return
+						}
+						conn.Close()
+					}
+				}()
+			} else {
+				// Keep the socket file, so that connecting to it is refused.
+				l.(*net.UnixListener).SetUnlinkOnClose(false)
+				require.NoError(t, l.Close(), "Setup: failed to close listener")
+			}
+
+			got, err := outNSSCommandForLib(t, socketPath, "", nil, "sh", "-c", "getent passwd user1 2>&1")
+			require.Error(t, err, "Lookup should fail when the daemon can't be reached")
+
+			require.Contains(t, got, fmt.Sprintf("could not connect to authd: socket=%s ", socketPath), "Logs should contain the socket path")
+			for _, field := range tc.wantFields {
+				require.Contains(t, got, field, "Logs should contain the connection failure details")
+			}
+		})
+	}
+}
+
+func TestSocketOwnership(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		dirMode os.FileMode
+
+		wantIgnored bool
+	}{
+		"Use socket from env in a private directory": {dirMode: 0700},
+
+		"Ignore missing socket from env in a directory writable by others": {dirMode: 0777, wantIgnored: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			dir := t.TempDir()
+			require.NoError(t, os.Chmod(dir, tc.dirMode), "Setup: failed to change the socket dir mode")
+			socketPath := filepath.Join(dir, "missing.sock")
+
+			got, err := outNSSCommandForLib(t, socketPath, "", nil, "sh", "-c", "getent passwd user1 2>&1")
+			require.Error(t, err, "Lookup should fail when the socket does not exist")
+
+			if tc.wantIgnored {
+				require.Contains(t, got, fmt.Sprintf("ignoring AUTHD_NSS_SOCKET %s", socketPath), "Socket from env should be ignored")
+				return
+			}
+			require.NotContains(t, got, "ignoring AUTHD_NSS_SOCKET", "Socket from env should be used")
+			require.Contains(t, got, socketPath, "Logs should mention the socket from env")
+		})
+	}
+}
+
+func TestAbstractSocket(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	// Abstract sockets don't exist on the filesystem, so their name only needs to be unique on the system.
+	socketPath := fmt.Sprintf("@authd-nss-tests-%d-%d", os.Getpid(), time.Now().UnixNano())
+	l, err := net.Listen("unix", socketPath)
+	require.NoError(t, err, "Setup: failed to listen on abstract socket")
+	t.Cleanup(func() { l.Close() })
+	requests := runStatusServer(t, l, codes.OK)
+
+	got, err := outNSSCommandForLib(t, socketPath, "", nil, "getent", "passwd", "user1")
+	require.NoError(t, err, "Lookup should succeed through the abstract socket")
+	require.Equal(t, "user1:x:1111:11111::/home/user1:/bin/bash\n", got, "Lookup should return the user of the daemon")
+	require.Equal(t, 1, requests(), "Lookup should reach authd through the abstract socket")
+}
+
+func TestConfigFile(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	ctx, cancel := context.WithCancel(context.Background())
+	socketPath, daemonStopped := runDaemon(ctx, t, "multiple_users_and_groups")
+	t.Cleanup(func() {
+		cancel()
+		<-daemonStopped
+	})
+
+	tests := map[string]struct {
+		config     string
+		configMode os.FileMode
+		env        []string
+
+		wantErr bool
+	}{
+		"Get entry from passwd by name when another user is denied in config file": {config: "AUTHD_NSS_DENY_USERS=root,user2"},
+		"Get entry from passwd by name when env takes precedence over config file":  {config: "AUTHD_NSS_DENY_USERS=user1", env: []string{"AUTHD_NSS_DENY_USERS=user2"}},
+		"Get entry from passwd by name when config file is writable by others":      {config: "AUTHD_NSS_DENY_USERS=user1", configMode: 0666},
+
+		"Error when getting passwd by name and user is denied in config file":      {config: "# Local accounts\nAUTHD_NSS_DENY_USERS=root,user1", wantErr: true},
+		"Error when getting passwd by name and user is not allowed in config file": {config: "AUTHD_NSS_ALLOW_USERS=user2", wantErr: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			if tc.configMode == 0 {
+				tc.configMode = 0600
+			}
+			configPath := filepath.Join(t.TempDir(), "nss.conf")
+			err := os.WriteFile(configPath, []byte(tc.config+"\n"), tc.configMode)
+			require.NoError(t, err, "Setup: failed to write config file")
+			// WriteFile is subject to the umask, so the mode is set explicitly.
+			require.NoError(t, os.Chmod(configPath, tc.configMode), "Setup: failed to change config file mode")
+
+			env := append([]string{"AUTHD_NSS_CONFIG_FILE=" + configPath}, tc.env...)
+			_, err = outNSSCommandForLib(t, socketPath, "", env, "getent", "passwd", "user1")
+			if tc.wantErr {
+				require.Error(t, err, "Expected an error, but got none")
+				return
+			}
+			require.NoError(t, err, "Expected no error, but got %v", err)
+		})
+	}
+}
+
+func TestOverridesFile(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	l, socketPath := listenUnix(t)
+	runStatusServer(t, l, codes.OK)
+
+	tests := map[string]struct {
+		overridesMode os.FileMode
+
+		wantShell string
+	}{
+		"Get entry from passwd by name with overridden shell":                     {overridesMode: 0600, wantShell: "/bin/rbash"},
+		"Get entry from passwd by name when overrides file is writable by others": {overridesMode: 0666, wantShell: "/bin/bash"},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			overridesPath := filepath.Join(t.TempDir(), "nss-overrides.conf")
+			err := os.WriteFile(overridesPath, []byte("user1 shell=/bin/rbash\n"), tc.overridesMode)
+			require.NoError(t, err, "Setup: failed to write overrides file")
+			// WriteFile is subject to the umask, so the mode is set explicitly.
+			require.NoError(t, os.Chmod(overridesPath, tc.overridesMode), "Setup: failed to change overrides file mode")
+
+			env := []string{"AUTHD_NSS_OVERRIDES_FILE=" + overridesPath}
+			got, err := outNSSCommandForLib(t, socketPath, "", env, "getent", "passwd", "user1")
+			require.NoError(t, err, "Expected no error, but got %v", err)
+			require.Equal(t, fmt.Sprintf("user1:x:1111:11111::/home/user1:%s\n", tc.wantShell), got, "Shell should only be overridden by a trusted file")
+		})
+	}
+}
+
+func TestSyslogIdent(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		env []string
+
+		wantIdent string
+	}{
+		"Tag syslog messages with authd by default":     {wantIdent: "authd"},
+		"Tag syslog messages with the given identifier": {env: []string{"AUTHD_NSS_SYSLOG_IDENT=my-ident"}, wantIdent: "my-ident"},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			syslogPath := tempSocketPath(t, "syslog.sock")
+			conn, err := net.ListenUnixgram("unixgram", &net.UnixAddr{Name: syslogPath, Net: "unixgram"})
+			require.NoError(t, err, "Setup: failed to listen on syslog socket")
+			t.Cleanup(func() { conn.Close() })
+
+			env := append([]string{"AUTHD_NSS_DEBUG=syslog", "AUTHD_NSS_SYSLOG_SOCKET=" + syslogPath}, tc.env...)
+			socketPath := filepath.Join(t.TempDir(), "missing.sock")
+			_, err = outNSSCommandForLib(t, socketPath, "", env, "getent", "passwd", "user1")
+			require.Error(t, err, "Lookup should fail when the socket does not exist")
+
+			// The messages were sent before the command exited, so they are already queued on the socket.
+			require.NoError(t, conn.SetReadDeadline(time.Now().Add(time.Second)), "Setup: failed to set read deadline")
+			buf := make([]byte, 4096)
+			n, err := conn.Read(buf)
+			require.NoError(t, err, "Syslog should have received a message")
+			require.Regexp(t, fmt.Sprintf(`^<\d+>.* %s\[\d+\]: `, regexp.QuoteMeta(tc.wantIdent)), string(buf[:n]), "Message should be tagged with the identifier")
+		})
+	}
+}
+
+func TestLookupLogFields(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		db  string
+		key string
+
+		wantFields string
+	}{
+		"Log operation and name of passwd lookups by name": {db: "passwd", key: "user1", wantFields: "op=passwd_by_name name=user1"},
+		"Log operation and uid of passwd lookups by uid":   {db: "passwd", key: "1111", wantFields: "op=passwd_by_uid uid=1111"},
+		"Log operation and gid of group lookups by gid":    {db: "group", key: "11111", wantFields: "op=group_by_gid gid=11111"},
+		"Log operation and user of initgroups lookups":     {db: "initgroups", key: "user1", wantFields: "op=initgroups user=user1"},
+		"Log operation of enumerations":                    {db: "passwd", wantFields: "op=passwd_entries"},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			socketPath := filepath.Join(t.TempDir(), "missing.sock")
+
+			// The lookups fail, but the connection failures are logged as part of them.
+			got, _ := outNSSCommandForLib(t, socketPath, "", nil, "sh", "-c", fmt.Sprintf("getent %s %s 2>&1", tc.db, tc.key))
+
+			require.Contains(t, got, "could not connect to gRPC server: ", "Logs should contain the connection failure")
+			for _, line := range strings.Split(got, "\n") {
+				if strings.Contains(line, "could not connect to gRPC server: ") {
+					require.True(t, strings.HasSuffix(line, " "+tc.wantFields), "Log line %q should end with the lookup fields", line)
+				}
+			}
+		})
+	}
+}
+
+func TestLogPid(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	socketPath := filepath.Join(t.TempDir(), "missing.sock")
+	env := []string{"AUTHD_NSS_LOG_PID=1"}
+
+	// Only keep the logs, which are printed on stderr.
+	got, _ := outNSSCommandForLib(t, socketPath, "", env, "sh", "-c", "getent passwd user1 2>&1 >/dev/null")
+
+	lines := strings.Split(strings.TrimSpace(got), "\n")
+	require.NotEmpty(t, lines, "Lookup should be logged")
+	for _, line := range lines {
+		require.Regexp(t, `authd\[getent:\d+\]: `, line, "Log line should be prefixed with the process name and pid")
+	}
+}
+
+func TestLogPidOfForkedChild(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	lookupsPath := buildTestProgram(t, "forked_lookups")
+
+	socketPath := filepath.Join(t.TempDir(), "missing.sock")
+	env := []string{"AUTHD_NSS_LOG_PID=1"}
+
+	// Only keep the logs and the pids, which are printed on stderr.
+	got, err := outNSSCommandForLib(t, socketPath, "", env, "sh", "-c", fmt.Sprintf("%s user1 2>&1 >/dev/null", lookupsPath))
+	require.NoError(t, err, "Forked lookups program should not fail: %s", got)
+
+	parent := regexp.MustCompile(`(?m)^parent pid: (\d+)$`).FindStringSubmatch(got)
+	require.NotNil(t, parent, "Output should contain the pid of the parent")
+	child := regexp.MustCompile(`(?m)^child pid: (\d+)$`).FindStringSubmatch(got)
+	require.NotNil(t, child, "Output should contain the pid of the child")
+
+	require.Contains(t, got, fmt.Sprintf("authd[forked_lookups:%s]: ", parent[1]), "Parent should log its own pid")
+	require.Contains(t, got, fmt.Sprintf("authd[forked_lookups:%s]: ", child[1]), "Child should log its own pid")
+}
+
+func TestJSONLogs(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	socketPath := filepath.Join(t.TempDir(), "missing.sock")
+	env := []string{"AUTHD_NSS_LOG=json"}
+
+	// Only keep the logs, which are printed on stderr.
+	got, _ := outNSSCommandForLib(t, socketPath, "", env, "sh", "-c", "getent passwd user1 2>&1 >/dev/null")
+
+	var messages, lookups int
+	for _, line := range strings.Split(strings.TrimSpace(got), "\n") {
+		var entry map[string]any
+		require.NoError(t, json.Unmarshal([]byte(line), &entry), "Log line %q should be a JSON object", line)
+		require.Contains(t, entry, "ts", "Log line %q should have a timestamp", line)
+		require.Contains(t, entry, "level", "Log line %q should have a level", line)
+
+		switch entry["kind"] {
+		case "message":
+			require.Contains(t, entry, "msg", "Message %q should have a text", line)
+			messages++
+		case "passwd_by_name":
+			require.Equal(t, "user1", entry["key"], "Lookup %q should have the looked up name as key", line)
+			require.Contains(t, entry, "outcome", "Lookup %q should have an outcome", line)
+			require.Contains(t, entry, "elapsed_ms", "Lookup %q should have a duration", line)
+			require.Contains(t, entry, "request_id", "Lookup %q should have a request id", line)
+			lookups++
+		default:
+			require.Fail(t, "Unexpected log line kind", "Log line: %q", line)
+		}
+	}
+	require.NotZero(t, messages, "Logs should contain the messages of the lookup")
+	require.Equal(t, 1, lookups, "Logs should contain a single lookup event")
+}
+
+func TestLogToFile(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		unwritable bool
+		symlink    bool
+	}{
+		"Append logs to the given file": {},
+
+		"Log to stderr when the file can not be opened": {unwritable: true},
+		"Log to stderr when the file is a symlink":      {symlink: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			logPath := filepath.Join(t.TempDir(), "nss.log")
+			if tc.unwritable {
+				logPath = filepath.Join(t.TempDir(), "doesnotexist", "nss.log")
+			}
+			var target string
+			if tc.symlink {
+				target = filepath.Join(t.TempDir(), "target.log")
+				require.NoError(t, os.WriteFile(target, nil, 0600), "Setup: failed to create symlink target")
+				require.NoError(t, os.Symlink(target, logPath), "Setup: failed to create log file symlink")
+			}
+			socketPath := filepath.Join(t.TempDir(), "missing.sock")
+
+			env := []string{"AUTHD_NSS_DEBUG=file:" + logPath}
+			got, err := outNSSCommandForLib(t, socketPath, "", env, "sh", "-c", "getent passwd user1 2>&1")
+			require.Error(t, err, "Lookup should fail when the socket does not exist")
+
+			if tc.unwritable || tc.symlink {
+				require.Contains(t, got, "cannot open log file", "Logs should warn that the file could not be opened")
+				require.Contains(t, got, "could not connect to gRPC server", "Logs should be printed to stderr")
+			}
+			if tc.symlink {
+				logs, err := os.ReadFile(target)
+				require.NoError(t, err, "Setup: failed to read symlink target")
+				require.Empty(t, logs, "Logs should not be written through the symlink")
+			}
+			if tc.unwritable || tc.symlink {
+				return
+			}
+
+			require.NotContains(t, got, "could not connect to gRPC server", "Logs should not be printed to stderr")
+			logs, err := os.ReadFile(logPath)
+			require.NoError(t, err, "Log file should have been created")
+			require.Contains(t, string(logs), "could not connect to gRPC server", "Logs should be appended to the file")
+		})
+	}
+}
+
+func TestRetries(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		codes []codes.Code
+
+		wantRequests int
+		wantErr      bool
+	}{
+		"Get entry after daemon is unavailable":            {codes: []codes.Code{codes.Unavailable, codes.OK}, wantRequests: 2},
+		"Get entry after daemon answered too late":         {codes: []codes.Code{codes.DeadlineExceeded, codes.OK}, wantRequests: 2},
+		"Get entry after two transient errors":             {codes: []codes.Code{codes.Unavailable, codes.DeadlineExceeded, codes.OK}, wantRequests: 3},
+		"Error when daemon is unavailable on all attempts": {codes: []codes.Code{codes.Unavailable}, wantRequests: 3, wantErr: true},
+		"Error without retrying on non transient errors":   {codes: []codes.Code{codes.InvalidArgument, codes.OK}, wantRequests: 1, wantErr: true},
+		"Error without retrying on errors of the daemon":   {codes: []codes.Code{codes.Unknown, codes.OK}, wantRequests: 1, wantErr: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			l, socketPath := listenUnix(t)
+			requests := runStatusServer(t, l, tc.codes...)
+
+			_, err := outNSSCommandForLib(t, socketPath, "", nil, "getent", "passwd", "user1")
+			if tc.wantErr {
+				require.Error(t, err, "Expected an error, but got none")
+			} else {
+				require.NoError(t, err, "Lookup should succeed after retrying")
+			}
+
+			require.Equal(t, tc.wantRequests, requests(), "Unexpected number of requests received by authd")
+		})
+	}
+}
+
+func TestRestartGracePeriod(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		restarting bool
+		downFor    time.Duration
+		env        []string
+
+		wantErr bool
+	}{
+		"Get entry once daemon is back when it is restarting": {restarting: true, downFor: 500 * time.Millisecond},
+		"Get entry right away when daemon is not restarting":  {},
+
+		"Error right away when daemon is down and not restarting":        {downFor: time.Minute, wantErr: true},
+		"Error when daemon restarts for longer than the request timeout": {restarting: true, downFor: time.Minute, env: []string{"AUTHD_NSS_REQUEST_TIMEOUT_MS=500"}, wantErr: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			l, socketPath := listenUnix(t)
+			runStatusServer(t, l, codes.OK)
+
+			sentinel := filepath.Join(filepath.Dir(socketPath), "authd.restarting")
+			if tc.restarting {
+				require.NoError(t, os.WriteFile(sentinel, nil, 0600), "Setup: failed to create restart sentinel")
+			}
+			env := append([]string{"AUTHD_NSS_RESTART_SENTINEL=" + sentinel}, tc.env...)
+
+			// Moving the socket away makes the daemon unreachable until it's moved back.
+			if tc.downFor > 0 {
+				hidden := socketPath + ".hidden"
+				require.NoError(t, os.Rename(socketPath, hidden), "Setup: failed to hide socket")
+				timer := time.AfterFunc(tc.downFor, func() { _ = os.Rename(hidden, socketPath) })
+				t.Cleanup(func() { timer.Stop() })
+			}
+
+			start := time.Now()
+			_, err := outNSSCommandForLib(t, socketPath, "", env, "getent", "passwd", "user1")
+			if tc.wantErr {
+				require.Error(t, err, "Expected an error, but got none")
+				require.Less(t, time.Since(start), 5*time.Second, "Lookup should not wait past the request timeout")
+				return
+			}
+			require.NoError(t, err, "Lookup should succeed once the daemon is back")
+		})
+	}
+}
+
+func TestSelfTest(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	selfTestPath := buildTestProgram(t, "selftest", "-ldl")
+
+	tests := map[string]struct {
+		user     string
+		noDaemon bool
+
+		wantOutcome       string
+		wantPrecheckFails bool
+	}{
+		"Report success when listing entries":   {wantOutcome: "success"},
+		"Report success when looking up a user": {user: "user1", wantOutcome: "success"},
+		"Report missing user":                   {user: "doesnotexist", wantOutcome: "not found"},
+
+		"Report failing precheck when daemon is not available": {noDaemon: true, wantOutcome: "could not connect", wantPrecheckFails: true},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			socketPath := filepath.Join(t.TempDir(), "not-running.sock")
+			if !tc.noDaemon {
+				ctx, cancel := context.WithCancel(context.Background())
+				var daemonStopped chan struct{}
+				socketPath, daemonStopped = runDaemon(ctx, t, "multiple_users_and_groups")
+				t.Cleanup(func() {
+					cancel()
+					<-daemonStopped
+				})
+			}
+
+			cmds := []string{selfTestPath, libPath}
+			if tc.user != "" {
+				cmds = append(cmds, tc.user)
+			}
+			got, err := outNSSCommandForLib(t, socketPath, "", nil, cmds...)
+			require.NoError(t, err, "Self test program should not fail")
+
+			require.Contains(t, got, fmt.Sprintf("socket=%s ", socketPath), "Report should contain the socket path")
+			require.Contains(t, got, fmt.Sprintf("precheck_fails=%t ", tc.wantPrecheckFails), "Report should tell whether the precheck fails")
+			require.Contains(t, got, fmt.Sprintf("outcome=%s", tc.wantOutcome), "Report should contain the outcome")
+			if !tc.noDaemon {
+				require.NotContains(t, got, "rpc_ms=- ", "Report should contain the RPC time")
+			}
+		})
+	}
+}
+
+func TestInitgroupsLimit(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	initgroupsPath := buildTestProgram(t, "initgroups_limit", "-ldl")
+
+	ctx, cancel := context.WithCancel(context.Background())
+	socketPath, daemonStopped := runDaemon(ctx, t, "multiple_users_and_groups")
+	t.Cleanup(func() {
+		cancel()
+		<-daemonStopped
+	})
+
+	tests := map[string]struct {
+		start string
+		limit string
+
+		want string
+	}{
+		"Add all groups of user below the limit": {start: "0", limit: "10", want: "status=1 start=2 groups=22222,99999"},
+		"Add groups of user up to the limit":     {start: "0", limit: "1", want: "status=1 start=1 groups=22222"},
+		"Add groups of user after existing ones": {start: "1", limit: "2", want: "status=1 start=2 groups=0,22222"},
+
+		"Add no groups when the array is full":           {start: "2", limit: "2", want: "status=1 start=2 groups=0,0"},
+		"Add no groups when the array exceeds the limit": {start: "3", limit: "1", want: "status=1 start=3 groups=0,0,0"},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			got, err := outNSSCommandForLib(t, socketPath, "", nil, initgroupsPath, libPath, "user2", tc.start, tc.limit)
+			require.NoError(t, err, "Initgroups program should not fail")
+			require.Equal(t, tc.want, strings.TrimSpace(got), "Unexpected groups returned by the hook")
+		})
+	}
+}
+
+func TestInitgroupsFromMultipleBrokers(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	initgroupsPath := buildTestProgram(t, "initgroups_limit", "-ldl")
+
+	// The groups of user1 come from two brokers, which both have a group with gid 11111.
+	l, socketPath := listenUnix(t)
+	runGroupsServer(t, l,
+		&authd.GroupEntry{Name: "group1", Passwd: "x", Gid: 11111, Members: []string{"user1"}},
+		&authd.GroupEntry{Name: "group2", Passwd: "x", Gid: 22222, Members: []string{"user1", "user2"}},
+		&authd.GroupEntry{Name: "other-broker-group1", Passwd: "x", Gid: 11111, Members: []string{"user1"}},
+		&authd.GroupEntry{Name: "other-broker-group3", Passwd: "x", Gid: 33333, Members: []string{"user1"}},
+		&authd.GroupEntry{Name: "other-broker-group4", Passwd: "x", Gid: 44444, Members: []string{"user2"}},
+	)
+
+	tests := map[string]struct {
+		limit string
+
+		want string
+	}{
+		"Merge the groups of all brokers without duplicates":                          {limit: "10", want: "status=1 start=3 groups=11111,22222,33333"},
+		"Merge the groups of all brokers up to the limit without counting duplicates": {limit: "3", want: "status=1 start=3 groups=11111,22222,33333"},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			got, err := outNSSCommandForLib(t, socketPath, "", nil, initgroupsPath, libPath, "user1", "0", tc.limit)
+			require.NoError(t, err, "Initgroups program should not fail")
+			require.Equal(t, tc.want, strings.TrimSpace(got), "Unexpected groups returned by the hook")
+		})
+	}
+}
+
+func TestInitgroupsCache(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+
+	tests := map[string]struct {
+		env []string
+
+		wantRequests int
+	}{
+		"List the groups once for a user looked up twice when enumeration cache is enabled": {env: []string{"AUTHD_NSS_ENUMERATION_CACHE_TTL=60"}, wantRequests: 1},
+
+		"List the groups each time when enumeration cache is disabled": {wantRequests: 2},
+	}
+	for name, tc := range tests {
+		tc := tc
+		t.Run(name, func(t *testing.T) {
+			t.Parallel()
+
+			l, socketPath := listenUnix(t)
+			requests := runGroupsServer(t, l,
+				&authd.GroupEntry{Name: "group1", Passwd: "x", Gid: 11111, Members: []string{"user1"}},
+			)
+
+			// getent looks up each of the keys in the same process.
+			got, err := outNSSCommandForLib(t, socketPath, "", tc.env, "getent", "initgroups", "user1", "user1")
+			require.NoError(t, err, "Initgroups lookups should not fail")
+			require.Equal(t, 2, strings.Count(got, " 11111"), "Both lookups should return the group of the user")
+
+			require.Equal(t, tc.wantRequests, requests(), "Unexpected number of requests received by authd")
+		})
+	}
+}
+
+// buildTestProgram builds the C program testdata/<name>.c, linked with the given libraries, and returns its
+// path.
+func buildTestProgram(t *testing.T, name string, libs ...string) string {
+	t.Helper()
+
+	path := filepath.Join(t.TempDir(), name)
+	args := append([]string{"-o", path, filepath.Join("testdata", name+".c")}, libs...)
+	//#nosec:G204 - We control the cmd arguments in tests.
+	out, err := exec.Command("cc", args...).CombinedOutput()
+	require.NoError(t, err, "Setup: failed to build %s program: %s", name, out)
+
+	return path
+}
+
+// nssSymbols returns the sorted NSS entry points exported by the library at path.
+func nssSymbols(t *testing.T, path string) (symbols []string) {
+	t.Helper()
+
+	f, err := elf.Open(path)
+	require.NoError(t, err, "Setup: failed to open library %s", path)
+	defer f.Close()
+
+	syms, err := f.DynamicSymbols()
+	require.NoError(t, err, "Setup: failed to read symbols of library %s", path)
+	for _, s := range syms {
+		if strings.HasPrefix(s.Name, "_nss_authd_") && s.Section != elf.SHN_UNDEF {
+			symbols = append(symbols, s.Name)
+		}
+	}
+	sort.Strings(symbols)
+
+	return symbols
+}
+
+func TestMetrics(t *testing.T) {
+	t.Parallel()
+
+	buildRustNSSLib(t)
+	selfTestPath := buildTestProgram(t, "selftest", "-ldl")
+
+	ctx, cancel := context.WithCancel(context.Background())
+	socketPath, daemonStopped := runDaemon(ctx, t, "multiple_users_and_groups")
+	t.Cleanup(func() {
+		cancel()
+		<-daemonStopped
+	})
+
+	got, err := outNSSCommandForLib(t, socketPath, "", nil, selfTestPath, libPath, "user1")
+	require.NoError(t, err, "Self test program should not fail")
+
+	for _, histogram := range []string{"connect_latency", "request_latency"} {
+		m := regexp.MustCompile(histogram + `=(\S+)`).FindStringSubmatch(got)
+		require.NotNil(t, m, "Metrics should contain the %s histogram", histogram)
+
+		// The self test connects and sends a single request, which a local daemon answers in well under a second.
+		var total int
+		for _, bucket := range strings.Split(m[1], ",") {
+			bound, count, found := strings.Cut(bucket, ":")
+			require.True(t, found, "Bucket %q should have a count", bucket)
+			n, err := strconv.Atoi(count)
+			require.NoError(t, err, "Bucket count should be a number")
+			if bound == "inf" {
+				require.Zero(t, n, "No %s latency should be above the last bucket", histogram)
+			}
+			total += n
+		}
+		require.Equal(t, 1, total, "%s should have recorded a single latency", histogram)
+	}
+}
+
 func TestMain(m *testing.M) {
 	testutils.InstallUpdateFlag()
 	flag.Parse()
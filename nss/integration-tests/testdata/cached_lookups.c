@@ -0,0 +1,35 @@
+// cached_lookups looks up the users given as arguments in order, from the same process. An argument of
+// the form +N sleeps for N seconds instead. If the module records metrics, they are printed once all
+// the lookups are done.
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <pwd.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+int main(int argc, char *argv[]) {
+	if (argc < 2) {
+		fprintf(stderr, "usage: %s USER|+SECONDS...\n", argv[0]);
+		return 1;
+	}
+
+	for (int i = 1; i < argc; i++) {
+		if (argv[i][0] == '+') {
+			sleep(atoi(argv[i] + 1));
+			continue;
+		}
+		getpwnam(argv[i]);
+	}
+
+	// The module is preloaded, so its symbols can be found without opening it again.
+	char *(*metrics)(void) = dlsym(RTLD_DEFAULT, "authd_nss_metrics");
+	void (*free_string)(char *) = dlsym(RTLD_DEFAULT, "authd_nss_free_string");
+	if (metrics != NULL && free_string != NULL) {
+		char *m = metrics();
+		printf("%s\n", m);
+		free_string(m);
+	}
+
+	return 0;
+}
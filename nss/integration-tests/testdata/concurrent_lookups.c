@@ -0,0 +1,49 @@
+// concurrent_lookups looks up the user given as first argument from the number of threads given as second
+// argument, all starting at the same time.
+#include <pthread.h>
+#include <pwd.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static pthread_barrier_t barrier;
+static const char *user;
+
+static void *lookup(void *arg) {
+	(void)arg;
+	struct passwd pwd, *result;
+	char buf[4096];
+
+	pthread_barrier_wait(&barrier);
+	getpwnam_r(user, &pwd, buf, sizeof(buf), &result);
+	return NULL;
+}
+
+int main(int argc, char *argv[]) {
+	if (argc < 3) {
+		fprintf(stderr, "usage: %s USER THREADS\n", argv[0]);
+		return 1;
+	}
+
+	user = argv[1];
+	int n = atoi(argv[2]);
+	if (n <= 0) {
+		fprintf(stderr, "invalid number of threads: %s\n", argv[2]);
+		return 1;
+	}
+
+	pthread_t *threads = calloc(n, sizeof(pthread_t));
+	pthread_barrier_init(&barrier, NULL, n);
+	for (int i = 0; i < n; i++) {
+		if (pthread_create(&threads[i], NULL, lookup, NULL) != 0) {
+			fprintf(stderr, "could not create thread %d\n", i);
+			return 1;
+		}
+	}
+	for (int i = 0; i < n; i++) {
+		pthread_join(threads[i], NULL);
+	}
+
+	pthread_barrier_destroy(&barrier);
+	free(threads);
+	return 0;
+}
@@ -0,0 +1,34 @@
+// forked_lookups looks up the user given as first argument, then forks a child which looks it up again.
+// The pids of both processes are printed on stderr, along with the logs of the lookups.
+#include <pwd.h>
+#include <stdio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+int main(int argc, char *argv[]) {
+	if (argc < 2) {
+		fprintf(stderr, "usage: %s USER\n", argv[0]);
+		return 1;
+	}
+
+	fprintf(stderr, "parent pid: %d\n", getpid());
+	getpwnam(argv[1]);
+
+	pid_t pid = fork();
+	if (pid < 0) {
+		perror("fork");
+		return 1;
+	}
+	if (pid == 0) {
+		getpwnam(argv[1]);
+		_exit(0);
+	}
+
+	fprintf(stderr, "child pid: %d\n", pid);
+	int status;
+	if (waitpid(pid, &status, 0) < 0 || !WIFEXITED(status) || WEXITSTATUS(status) != 0) {
+		fprintf(stderr, "child failed\n");
+		return 1;
+	}
+	return 0;
+}
@@ -0,0 +1,47 @@
+// initgroups_limit loads the NSS module given as first argument and calls its initgroups hook for the user
+// given as second argument, with an array of groups already filled up to the start given as third
+// argument and the limit given as fourth argument. It prints the status returned by the hook and the
+// groups of the array.
+#include <dlfcn.h>
+#include <errno.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/types.h>
+
+typedef int (*initgroups_dyn_fn)(const char *, gid_t, size_t *, size_t *, gid_t **, size_t, int *);
+
+int main(int argc, char *argv[]) {
+	if (argc < 5) {
+		fprintf(stderr, "usage: %s LIB USER START LIMIT\n", argv[0]);
+		return 1;
+	}
+
+	void *lib = dlopen(argv[1], RTLD_NOW);
+	if (lib == NULL) {
+		fprintf(stderr, "%s\n", dlerror());
+		return 1;
+	}
+
+	initgroups_dyn_fn initgroups_dyn = (initgroups_dyn_fn)dlsym(lib, "_nss_authd_initgroups_dyn");
+	if (initgroups_dyn == NULL) {
+		fprintf(stderr, "%s\n", dlerror());
+		return 1;
+	}
+
+	size_t start = strtoul(argv[3], NULL, 10);
+	size_t limit = strtoul(argv[4], NULL, 10);
+	size_t size = start > 0 ? start : 1;
+	gid_t *groups = calloc(size, sizeof(gid_t));
+	int err = 0;
+
+	int status = initgroups_dyn(argv[2], (gid_t)-1, &start, &size, &groups, limit, &err);
+
+	printf("status=%d start=%zu groups=", status, start);
+	for (size_t i = 0; i < start; i++) {
+		printf("%s%u", i > 0 ? "," : "", groups[i]);
+	}
+	printf("\n");
+
+	free(groups);
+	return 0;
+}
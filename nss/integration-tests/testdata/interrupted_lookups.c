@@ -0,0 +1,76 @@
+// interrupted_lookups looks up the user given as first argument the number of times given as second
+// argument. Each lookup is done from a new child process, so that it connects to authd, while a timer
+// keeps interrupting its system calls with a signal handled without SA_RESTART.
+#include <pwd.h>
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/time.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+static void on_alarm(int sig) {
+	(void)sig;
+}
+
+static int lookup(const char *user) {
+	struct sigaction sa;
+	memset(&sa, 0, sizeof(sa));
+	sa.sa_handler = on_alarm;
+	if (sigaction(SIGALRM, &sa, NULL) != 0) {
+		perror("sigaction");
+		return 1;
+	}
+
+	struct itimerval timer = {
+		.it_interval = {.tv_usec = 100},
+		.it_value = {.tv_usec = 100},
+	};
+	if (setitimer(ITIMER_REAL, &timer, NULL) != 0) {
+		perror("setitimer");
+		return 1;
+	}
+
+	if (getpwnam(user) == NULL) {
+		fprintf(stderr, "could not look up %s\n", user);
+		return 1;
+	}
+	return 0;
+}
+
+int main(int argc, char *argv[]) {
+	if (argc < 3) {
+		fprintf(stderr, "usage: %s USER LOOKUPS\n", argv[0]);
+		return 1;
+	}
+
+	int n = atoi(argv[2]);
+	if (n <= 0) {
+		fprintf(stderr, "invalid number of lookups: %s\n", argv[2]);
+		return 1;
+	}
+
+	int failed = 0;
+	for (int i = 0; i < n; i++) {
+		pid_t pid = fork();
+		if (pid < 0) {
+			perror("fork");
+			return 1;
+		}
+		if (pid == 0) {
+			_exit(lookup(argv[1]));
+		}
+
+		int status;
+		if (waitpid(pid, &status, 0) < 0 || !WIFEXITED(status) || WEXITSTATUS(status) != 0) {
+			failed++;
+		}
+	}
+
+	if (failed > 0) {
+		fprintf(stderr, "%d of %d lookups failed\n", failed, n);
+		return 1;
+	}
+	return 0;
+}
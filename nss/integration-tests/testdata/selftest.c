@@ -0,0 +1,38 @@
+// selftest loads the NSS module given as first argument and prints the report of its self test,
+// looking up the user given as second argument, if any. If the module records metrics, they are
+// printed on a second line.
+#include <dlfcn.h>
+#include <stdio.h>
+
+int main(int argc, char *argv[]) {
+	if (argc < 2) {
+		fprintf(stderr, "usage: %s LIB [USER]\n", argv[0]);
+		return 1;
+	}
+
+	void *lib = dlopen(argv[1], RTLD_NOW);
+	if (lib == NULL) {
+		fprintf(stderr, "%s\n", dlerror());
+		return 1;
+	}
+
+	char *(*self_test)(const char *) = dlsym(lib, "authd_nss_self_test");
+	void (*free_string)(char *) = dlsym(lib, "authd_nss_free_string");
+	if (self_test == NULL || free_string == NULL) {
+		fprintf(stderr, "%s\n", dlerror());
+		return 1;
+	}
+
+	char *report = self_test(argc > 2 ? argv[2] : NULL);
+	printf("%s\n", report);
+	free_string(report);
+
+	char *(*metrics)(void) = dlsym(lib, "authd_nss_metrics");
+	if (metrics != NULL) {
+		char *m = metrics();
+		printf("%s\n", m);
+		free_string(m);
+	}
+
+	return 0;
+}
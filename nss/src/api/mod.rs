@@ -0,0 +1,208 @@
+use libnss::group::Group;
+use libnss::passwd::Passwd;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use tonic::{Code, Response, Status};
+
+use crate::client::{self, authd, ClientError};
+use crate::policy;
+
+/// LookupError tells why a lookup made through the Rust API failed, so that callers can decide whether
+/// to retry it or to report it to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LookupError {
+    /// Transient errors may go away by retrying later, like when authd is not running or overloaded.
+    Transient(String),
+    /// Permanent errors will happen again on retry, like when the daemon and the module versions differ.
+    Permanent(String),
+    /// NotFound means that authd doesn't know the requested entry.
+    NotFound,
+    /// Policy means that the module is configured not to answer for the requested entry, or that the
+    /// lookup must not reach authd, like when it's performed by systemd on behalf of dbus.
+    Policy,
+}
+
+impl LookupError {
+    /// from_status returns the error matching the gRPC status returned by authd.
+    ///
+    /// ```
+    /// use nss_authd::LookupError;
+    /// use tonic::{Code, Status};
+    ///
+    /// assert_eq!(LookupError::from_status(&Status::not_found("")), LookupError::NotFound);
+    /// for code in [Code::Unavailable, Code::DeadlineExceeded, Code::ResourceExhausted, Code::Unknown] {
+    ///     let err = LookupError::from_status(&Status::new(code, "oops"));
+    ///     assert_eq!(err, LookupError::Transient("oops".to_string()), "{:?} should be transient", code);
+    /// }
+    /// for code in [Code::Internal, Code::InvalidArgument, Code::Unimplemented, Code::PermissionDenied] {
+    ///     let err = LookupError::from_status(&Status::new(code, "oops"));
+    ///     assert_eq!(err, LookupError::Permanent("oops".to_string()), "{:?} should be permanent", code);
+    /// }
+    /// ```
+    pub fn from_status(status: &Status) -> Self {
+        let message = status.message().to_string();
+        match status.code() {
+            Code::NotFound => LookupError::NotFound,
+            Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Aborted
+            | Code::Cancelled
+            | Code::Unknown => LookupError::Transient(message),
+            _ => LookupError::Permanent(message),
+        }
+    }
+
+    /// from_client_error returns the error matching the failure to connect to authd.
+    fn from_client_error(err: ClientError) -> Self {
+        match err {
+            ClientError::Bypassed => LookupError::Policy,
+            err => LookupError::Transient(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupError::Transient(msg) => write!(f, "temporary failure: {}", msg),
+            LookupError::Permanent(msg) => write!(f, "permanent failure: {}", msg),
+            LookupError::NotFound => write!(f, "entry not found"),
+            LookupError::Policy => write!(f, "entry not allowed by the NSS module policy"),
+        }
+    }
+}
+
+impl Error for LookupError {}
+
+/// passwd_by_name returns the passwd entry of the user with the given name.
+pub fn passwd_by_name(name: &str) -> Result<Passwd, LookupError> {
+    if !policy::is_name_allowed(name) {
+        return Err(LookupError::Policy);
+    }
+    let req = authd::GetByNameRequest {
+        name: name.to_string(),
+    };
+    let entry = call(|mut c| {
+        let req = req.clone();
+        async move { c.get_passwd_by_name(req).await }
+    })?;
+    allowed_passwd(entry)
+}
+
+/// passwd_by_uid returns the passwd entry of the user with the given uid.
+pub fn passwd_by_uid(uid: u32) -> Result<Passwd, LookupError> {
+    if !policy::is_uid_allowed(uid) {
+        return Err(LookupError::Policy);
+    }
+    let entry =
+        call(|mut c| async move { c.get_passwd_by_uid(authd::GetByIdRequest { id: uid }).await })?;
+    allowed_passwd(entry)
+}
+
+/// group_by_name returns the group entry of the group with the given name.
+pub fn group_by_name(name: &str) -> Result<Group, LookupError> {
+    let req = authd::GetByNameRequest {
+        name: name.to_string(),
+    };
+    let entry = call(|mut c| {
+        let req = req.clone();
+        async move { c.get_group_by_name(req).await }
+    })?;
+    allowed_group(entry)
+}
+
+/// group_by_gid returns the group entry of the group with the given gid.
+pub fn group_by_gid(gid: u32) -> Result<Group, LookupError> {
+    let entry =
+        call(|mut c| async move { c.get_group_by_gid(authd::GetByIdRequest { id: gid }).await })?;
+    allowed_group(entry)
+}
+
+/// allowed_passwd converts the entry returned by authd, unless the module is not allowed to answer it.
+fn allowed_passwd(entry: authd::PasswdEntry) -> Result<Passwd, LookupError> {
+    if !policy::is_name_allowed(&entry.name)
+        || !policy::is_uid_allowed(entry.uid)
+        || !policy::is_id_in_range(entry.uid)
+        || !policy::is_id_in_range(entry.gid)
+    {
+        return Err(LookupError::Policy);
+    }
+    Ok(Passwd {
+        name: entry.name,
+        passwd: entry.passwd,
+        uid: entry.uid,
+        gid: entry.gid,
+        gecos: entry.gecos,
+        dir: entry.homedir,
+        shell: entry.shell,
+    })
+}
+
+/// allowed_group converts the entry returned by authd, unless the module is not allowed to answer it.
+fn allowed_group(entry: authd::GroupEntry) -> Result<Group, LookupError> {
+    if !policy::is_id_in_range(entry.gid) {
+        return Err(LookupError::Policy);
+    }
+    Ok(Group {
+        name: entry.name,
+        passwd: entry.passwd,
+        gid: entry.gid,
+        members: entry.members,
+    })
+}
+
+/// call connects to authd and performs the request made by f.
+fn call<T, F, Fut>(mut f: F) -> Result<T, LookupError>
+where
+    F: FnMut(authd::nss_client::NssClient<tonic::transport::Channel>) -> Fut,
+    Fut: Future<Output = Result<Response<T>, Status>>,
+{
+    let rt = client::runtime()
+        .map_err(|e| LookupError::Transient(format!("could not create runtime: {}", e)))?;
+
+    rt.block_on(async {
+        let client = client::new_client()
+            .await
+            .map_err(LookupError::from_client_error)?;
+        client::call_with_retry(|| f(client.clone()))
+            .await
+            .map_err(|status| LookupError::from_status(&status))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_client_error_maps_the_connection_failures() {
+        let socket = "/run/authd.sock".to_string();
+        let tests = [
+            (ClientError::Bypassed, LookupError::Policy),
+            (
+                ClientError::SocketMissing(vec![socket.clone()]),
+                LookupError::Transient(
+                    "none of the sockets /run/authd.sock exist, is authd running?".to_string(),
+                ),
+            ),
+            (
+                ClientError::ConnectTimeout(socket),
+                LookupError::Transient(
+                    "timed out connecting to authd on /run/authd.sock".to_string(),
+                ),
+            ),
+        ];
+
+        for (err, want) in tests {
+            let name = format!("{:?}", err);
+            assert_eq!(
+                LookupError::from_client_error(err),
+                want,
+                "unexpected error for {}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use libnss::group::Group;
+use libnss::interop::Response;
+use libnss::passwd::Passwd;
+use libnss::shadow::Shadow;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::client;
+use crate::debug;
+
+/// DEFAULT_CACHE_SIZE is the default maximum number of entries of each cache.
+const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// GENERATION is increased each time the caches are invalidated.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// MISSING remembers the lookups for which authd answered that the entry is missing, whatever the
+/// database.
+///
+/// Keep its TTL short: authd creates users when they first log in, and until the TTL expires a process
+/// that looked up such a user before its creation keeps answering that it doesn't exist.
+static MISSING: Store<()> = Store::new(
+    "AUTHD_NSS_NEGATIVE_CACHE_TTL",
+    "AUTHD_NSS_NEGATIVE_CACHE_SIZE",
+);
+
+/// PASSWD caches the passwd entries found by authd.
+pub static PASSWD: Cache<Passwd> = Cache::new(
+    "passwd",
+    "AUTHD_NSS_PASSWD_CACHE_TTL",
+    "AUTHD_NSS_PASSWD_CACHE_SIZE",
+);
+
+/// GROUP caches the group entries found by authd.
+pub static GROUP: Cache<Group> = Cache::new(
+    "group",
+    "AUTHD_NSS_GROUP_CACHE_TTL",
+    "AUTHD_NSS_GROUP_CACHE_SIZE",
+);
+
+/// SHADOW caches the shadow entries found by authd.
+pub static SHADOW: Cache<Shadow> = Cache::new(
+    "shadow",
+    "AUTHD_NSS_SHADOW_CACHE_TTL",
+    "AUTHD_NSS_SHADOW_CACHE_SIZE",
+);
+
+/// PASSWD_ENTRIES caches the passwd entries listed by authd.
+pub static PASSWD_ENTRIES: Cache<Vec<Passwd>> = Cache::new(
+    "passwd_entries",
+    "AUTHD_NSS_ENUMERATION_CACHE_TTL",
+    "AUTHD_NSS_ENUMERATION_CACHE_SIZE",
+);
+
+/// GROUP_ENTRIES caches the group entries listed by authd.
+pub static GROUP_ENTRIES: Cache<Vec<Group>> = Cache::new(
+    "group_entries",
+    "AUTHD_NSS_ENUMERATION_CACHE_TTL",
+    "AUTHD_NSS_ENUMERATION_CACHE_SIZE",
+);
+
+/// SHADOW_ENTRIES caches the shadow entries listed by authd.
+pub static SHADOW_ENTRIES: Cache<Vec<Shadow>> = Cache::new(
+    "shadow_entries",
+    "AUTHD_NSS_ENUMERATION_CACHE_TTL",
+    "AUTHD_NSS_ENUMERATION_CACHE_SIZE",
+);
+
+/// CacheValue is implemented by the values which can be cached. The entries of libnss are not Clone,
+/// so they are duplicated field by field.
+pub trait CacheValue {
+    /// duplicate returns a copy of the value.
+    fn duplicate(&self) -> Self;
+}
+
+impl CacheValue for () {
+    fn duplicate(&self) -> Self {}
+}
+
+impl CacheValue for Passwd {
+    fn duplicate(&self) -> Self {
+        Passwd {
+            name: self.name.clone(),
+            passwd: self.passwd.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            gecos: self.gecos.clone(),
+            dir: self.dir.clone(),
+            shell: self.shell.clone(),
+        }
+    }
+}
+
+impl CacheValue for Group {
+    fn duplicate(&self) -> Self {
+        Group {
+            name: self.name.clone(),
+            passwd: self.passwd.clone(),
+            gid: self.gid,
+            members: self.members.clone(),
+        }
+    }
+}
+
+impl CacheValue for Shadow {
+    fn duplicate(&self) -> Self {
+        Shadow {
+            name: self.name.clone(),
+            passwd: self.passwd.clone(),
+            last_change: self.last_change,
+            change_min_days: self.change_min_days,
+            change_max_days: self.change_max_days,
+            change_warn_days: self.change_warn_days,
+            change_inactive_days: self.change_inactive_days,
+            expire_date: self.expire_date,
+            reserved: self.reserved,
+        }
+    }
+}
+
+impl<T: CacheValue> CacheValue for Vec<T> {
+    fn duplicate(&self) -> Self {
+        self.iter().map(CacheValue::duplicate).collect()
+    }
+}
+
+/// Entry is a cached value.
+struct Entry<T> {
+    /// added is when the value was cached, to expire it once its TTL elapsed.
+    added: Instant,
+    /// last_used orders the entries by last use, to evict the least recently used one.
+    last_used: u64,
+    value: T,
+}
+
+/// Entries holds the values of a store, keyed by lookup.
+struct Entries<T> {
+    /// pid is the process which performed the lookups. Forked children start with empty caches.
+    pid: u32,
+    /// generation is the value of GENERATION when the entries were added.
+    generation: u64,
+    /// uses counts the uses of the entries, to order them by last use.
+    uses: u64,
+    map: BTreeMap<String, Entry<T>>,
+}
+
+/// Store holds values for a TTL, up to a number of entries, both set by env variables.
+struct Store<T> {
+    ttl_var: &'static str,
+    size_var: &'static str,
+    entries: Mutex<Entries<T>>,
+}
+
+impl<T: CacheValue> Store<T> {
+    /// new returns an empty store configured by the env variables ttl_var and size_var.
+    const fn new(ttl_var: &'static str, size_var: &'static str) -> Self {
+        Store {
+            ttl_var,
+            size_var,
+            entries: Mutex::new(Entries {
+                pid: 0,
+                generation: 0,
+                uses: 0,
+                map: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// ttl returns for how long the values are kept. The store is disabled unless its TTL is set to a
+    /// positive number of seconds.
+    fn ttl(&self) -> Option<Duration> {
+        let secs = crate::setting(self.ttl_var).ok()?.parse::<u64>().ok()?;
+        if secs == 0 {
+            return None;
+        }
+        Some(Duration::from_secs(secs))
+    }
+
+    /// size returns the maximum number of values of the store, which defaults to DEFAULT_CACHE_SIZE.
+    fn size(&self) -> usize {
+        crate::setting(self.size_var)
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_CACHE_SIZE)
+    }
+
+    /// get returns a copy of the value of key, unless it's older than ttl.
+    fn get(&self, key: &str, ttl: Duration) -> Option<T> {
+        let mut entries = self.lock();
+        entries.uses += 1;
+        let uses = entries.uses;
+        match entries.map.get_mut(key) {
+            Some(entry) if entry.added.elapsed() < ttl => {
+                entry.last_used = uses;
+                Some(entry.value.duplicate())
+            }
+            Some(_) => {
+                entries.map.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// insert adds the value of key. If the store is full, the expired values are dropped first, then
+    /// the least recently used one.
+    fn insert(&self, key: &str, value: T, ttl: Duration) {
+        let size = self.size();
+        let mut entries = self.lock();
+        if !entries.map.contains_key(key) && entries.map.len() >= size {
+            entries.map.retain(|_, e| e.added.elapsed() < ttl);
+        }
+        while !entries.map.contains_key(key) && entries.map.len() >= size {
+            let lru = entries
+                .map
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            match lru {
+                Some(k) => {
+                    debug!("evicting {} from the cache", k);
+                    entries.map.remove(&k);
+                }
+                None => break,
+            }
+        }
+
+        entries.uses += 1;
+        let last_used = entries.uses;
+        entries.map.insert(
+            key.to_string(),
+            Entry {
+                added: Instant::now(),
+                last_used,
+                value,
+            },
+        );
+    }
+
+    /// remove drops the value of key.
+    fn remove(&self, key: &str) {
+        self.lock().map.remove(key);
+    }
+
+    /// lock locks the entries of the store, emptying them if they were added by the parent of the
+    /// process or before the caches were invalidated.
+    fn lock(&self) -> MutexGuard<'_, Entries<T>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let pid = std::process::id();
+        let generation = GENERATION.load(Ordering::SeqCst);
+        if entries.pid != pid || entries.generation != generation {
+            entries.pid = pid;
+            entries.generation = generation;
+            entries.map.clear();
+        }
+        entries
+    }
+}
+
+/// Cache holds the entries found by authd for the lookups of a database.
+pub struct Cache<T> {
+    /// name identifies the cache in the logs and the metrics.
+    name: &'static str,
+    found: Store<T>,
+}
+
+impl<T: CacheValue> Cache<T> {
+    /// new returns an empty cache named name, configured by the env variables ttl_var and size_var.
+    const fn new(name: &'static str, ttl_var: &'static str, size_var: &'static str) -> Self {
+        Cache {
+            name,
+            found: Store::new(ttl_var, size_var),
+        }
+    }
+}
+
+/// cached returns the response of the lookup f identified by key, from cache if possible.
+///
+/// The key identifies the lookup, like "passwd_by_name:user1". Entries found by authd are kept in cache
+/// for AUTHD_NSS_<DB>_CACHE_TTL seconds, entries authd answered are missing for
+/// AUTHD_NSS_NEGATIVE_CACHE_TTL seconds and the lists of entries for AUTHD_NSS_ENUMERATION_CACHE_TTL
+/// seconds. All the caches are disabled by default. The number of entries of each cache is bounded by
+/// the matching AUTHD_NSS_*_CACHE_SIZE, and defaults to DEFAULT_CACHE_SIZE.
+///
+/// Other responses, like authd being unavailable, are never cached. While authd is restarting, the
+/// caches are emptied and the lookups always reach authd, whose answers may change with the restart.
+pub fn cached<T: CacheValue>(
+    cache: &Cache<T>,
+    key: &str,
+    f: impl FnOnce() -> Response<T>,
+) -> Response<T> {
+    let found_ttl = cache.found.ttl();
+    let missing_ttl = MISSING.ttl();
+    if found_ttl.is_none() && missing_ttl.is_none() {
+        return f();
+    }
+    if client::is_restarting() {
+        debug!("authd is restarting, not using the cache");
+        invalidate();
+        return f();
+    }
+
+    if let Some(value) = found_ttl.and_then(|ttl| cache.found.get(key, ttl)) {
+        debug!("{} answered from the {} cache", key, cache.name);
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_cache(cache.name, true);
+        return Response::Success(value);
+    }
+    if missing_ttl.is_some_and(|ttl| MISSING.get(key, ttl).is_some()) {
+        debug!("{} is missing according to the negative cache", key);
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_cache(cache.name, true);
+        return Response::NotFound;
+    }
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_cache(cache.name, false);
+
+    let r = f();
+    match &r {
+        Response::Success(value) => {
+            // An entry found by authd is forgotten right away by the negative cache, so that it never
+            // hides an entry that authd knows about.
+            MISSING.remove(key);
+            if let Some(ttl) = found_ttl {
+                cache.found.insert(key, value.duplicate(), ttl);
+            }
+        }
+        Response::NotFound => {
+            cache.found.remove(key);
+            if let Some(ttl) = missing_ttl {
+                MISSING.insert(key, (), ttl);
+            }
+        }
+        _ => {}
+    }
+    r
+}
+
+/// invalidate empties all the caches of the process.
+fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// authd_nss_invalidate_cache empties all the caches of the process, so that the next lookups reach
+/// authd.
+///
+/// It only touches an atomic counter, so it is async-signal-safe and can be called from the SIGHUP
+/// handler of the process using the module.
+#[no_mangle]
+pub extern "C" fn authd_nss_invalidate_cache() {
+    invalidate();
+}
@@ -1,35 +1,1056 @@
 use authd::nss_client::NssClient;
+use libnss::interop::Response as NssResponse;
+use prost::Message;
 use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tokio::net::UnixStream;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::{Notify, Semaphore};
+use tonic::body::BoxBody;
+use tonic::codegen::http;
 use tonic::transport::{Channel, Endpoint, Uri};
-use tower::service_fn;
+use tonic::{Code, Response, Status};
+use tower::{service_fn, ServiceExt};
 
-use crate::debug;
+use crate::{debug, warn};
 
 pub mod authd {
     tonic::include_proto!("authd");
 }
 
+/// USER_AGENT identifies the requests made by the NSS module to authd.
+const USER_AGENT: &str = concat!("authd-nss/", env!("CARGO_PKG_VERSION"));
+
+/// CANCELLED wakes up the lookups in progress when they are cancelled.
+static CANCELLED: Notify = Notify::const_new();
+
+/// CachedChannel is the connection to authd shared by the lookups of the process.
+struct CachedChannel {
+    /// pid is the process which connected to authd. Forked children must not reuse the connection of
+    /// their parent.
+    pid: u32,
+    channel: Channel,
+}
+
+/// CHANNEL holds the connection to authd, if one was established.
+static CHANNEL: Mutex<Option<CachedChannel>> = Mutex::new(None);
+
+/// DEFAULT_MAX_CONCURRENT_CONNECTS is the default maximum number of connections to authd that a process
+/// establishes at the same time.
+const DEFAULT_MAX_CONCURRENT_CONNECTS: usize = 16;
+
+/// CONNECT_SLOTS bounds the number of connections to authd being established at the same time.
+static CONNECT_SLOTS: OnceLock<Semaphore> = OnceLock::new();
+
+/// RUNTIME holds the runtime running the lookups of the process, along with the process which built it.
+static RUNTIME: Mutex<Option<(u32, &'static Runtime)>> = Mutex::new(None);
+
+/// MAX_ATTEMPTS is the maximum number of times a request failing with a transient error is performed.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// RETRY_BACKOFF is the delay before the first retry of a request, when authd doesn't suggest one. It's
+/// doubled after each retry.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// RESTART_POLL_INTERVAL is how often the lookups held while authd is restarting try to reach it again.
+const RESTART_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// MAX_RETRY_DELAY is the longest delay suggested by authd that is waited before retrying a request.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// RETRY_INFO_TYPE_URL is the type of the status details carrying the delay suggested by the server
+/// before retrying a request.
+const RETRY_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.RetryInfo";
+
+/// RpcStatus is the google.rpc.Status message encoded in the details of a gRPC status.
+#[derive(Clone, PartialEq, Message)]
+struct RpcStatus {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<Any>,
+}
+
+/// Any is the google.protobuf.Any message.
+#[derive(Clone, PartialEq, Message)]
+struct Any {
+    #[prost(string, tag = "1")]
+    type_url: String,
+    #[prost(bytes = "vec", tag = "2")]
+    value: Vec<u8>,
+}
+
+/// RetryInfo is the google.rpc.RetryInfo message.
+#[derive(Clone, PartialEq, Message)]
+struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    retry_delay: Option<ProtoDuration>,
+}
+
+/// ProtoDuration is the google.protobuf.Duration message.
+#[derive(Clone, PartialEq, Message)]
+struct ProtoDuration {
+    #[prost(int64, tag = "1")]
+    seconds: i64,
+    #[prost(int32, tag = "2")]
+    nanos: i32,
+}
+
+/// runtime returns the runtime running the lookups, building it on first use.
+///
+/// All the lookups of a process share the same runtime, which also runs the connection to authd cached
+/// by new_client. A forked child can't use the runtime of its parent, as they would share its epoll
+/// instance, so a new one is built for it. That's why it's not kept in a OnceLock: the runtimes are
+/// leaked instead, one per process performing lookups.
+///
+/// The leak is bounded. A process only builds its own runtime once, and glibc never unloads the NSS
+/// modules, so it lives until the process exits. A forked child also keeps the copy of the runtime of
+/// its parent, which can't be dropped as its epoll instance is shared with the parent: that's only
+/// memory, as the threads of the parent are not forked, and the file descriptors of the runtime are
+/// closed when the child execs. Forking children never makes the parent leak more.
+pub fn runtime() -> io::Result<&'static Runtime> {
+    let mut cached = RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+    let pid = std::process::id();
+    if let Some((p, rt)) = *cached {
+        if p == pid {
+            return Ok(rt);
+        }
+    }
+
+    let rt: &'static Runtime = Box::leak(Box::new(
+        Builder::new_current_thread().enable_all().build()?,
+    ));
+    *cached = Some((pid, rt));
+    Ok(rt)
+}
+
+/// ClientError tells why the connection to authd could not be established.
+#[derive(Debug)]
+pub enum ClientError {
+    /// Bypassed means that the lookup is performed by systemd on behalf of dbus, and must not reach
+    /// authd.
+    Bypassed,
+    /// SocketMissing means that none of the given sockets exist, usually because authd is not running
+    /// (yet).
+    SocketMissing(Vec<String>),
+    /// ConnectTimeout means that authd didn't accept the connection on the given socket in time.
+    ConnectTimeout(String),
+    /// Transport means that connecting to authd on the given socket failed.
+    Transport(String, tonic::transport::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Bypassed => write!(f, "NSS lookup performed through systemd, skipping..."),
+            ClientError::SocketMissing(paths) => write!(
+                f,
+                "none of the sockets {} exist, is authd running?",
+                paths.join(", ")
+            ),
+            ClientError::ConnectTimeout(path) => {
+                write!(f, "timed out connecting to authd on {}", path)
+            }
+            ClientError::Transport(path, err) => {
+                write!(f, "could not connect to authd on {}: {}", path, err)
+            }
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Transport(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 /// new_client creates a new client connection to the gRPC server or returns an active one.
-pub async fn new_client() -> Result<NssClient<Channel>, Box<dyn Error>> {
+///
+/// If authd can't be reached while it is restarting, the connection is attempted again every
+/// RESTART_POLL_INTERVAL, until the request timeout expires.
+pub async fn new_client() -> Result<NssClient<Channel>, ClientError> {
     // We need to skip NSS lookups performed by dbus through systemd, otherwise
     // we could end up in a deadlock due to lookups happening while the authd
     // daemon is starting up.
     // This variable is set by systemd specifically for dbus.service to avoid a
     // similar issue with nss-systemd - we can repurpose it for our case.
     // ref: https://github.com/systemd/systemd/pull/22552
-    if std::env::var("SYSTEMD_NSS_DYNAMIC_BYPASS").is_ok() {
-        return Err("NSS lookup performed through systemd, skipping...".into());
+    if is_bypassed() {
+        return Err(ClientError::Bypassed);
+    }
+
+    if let Some(ch) = cached_channel().await {
+        return Ok(nss_client(ch));
+    }
+
+    // The semaphore is never closed, so acquiring a slot can't fail.
+    let _slot = connect_slots().acquire().await.ok();
+    // Another lookup may have connected while we were waiting for a slot.
+    if let Some(ch) = cached_channel().await {
+        return Ok(nss_client(ch));
+    }
+
+    let deadline = Instant::now() + super::request_timeout();
+    loop {
+        match connect_any().await {
+            Ok(ch) => return Ok(nss_client(ch)),
+            Err(err) if is_restarting() && Instant::now() + RESTART_POLL_INTERVAL < deadline => {
+                debug!("authd is restarting ({}), waiting for it...", err);
+                tokio::time::sleep(RESTART_POLL_INTERVAL).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// nss_client returns a client of authd using the channel ch, able to decode responses up to the
+/// configured size.
+fn nss_client(ch: Channel) -> NssClient<Channel> {
+    NssClient::new(ch).max_decoding_message_size(super::max_response_size())
+}
+
+/// connect_any connects to the first socket of authd accepting the connection, and caches the channel
+/// for the next lookups.
+async fn connect_any() -> Result<Channel, ClientError> {
+    let socket_paths = super::socket_paths();
+    let mut last_err = None;
+    for socket_path in &socket_paths {
+        // Skip the socket if the file is not there: authd is not listening on it, so there is no point in
+        // going through the connection attempt.
+        if is_socket_missing(socket_path) {
+            debug!("Socket {} does not exist, skipping it", socket_path);
+            continue;
+        }
+
+        let peer_pid = Arc::new(AtomicI32::new(0));
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = connect(socket_path.clone(), peer_pid.clone()).await;
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_connect(start.elapsed());
+
+        match result {
+            Ok(ch) => {
+                debug!("Connected to authd on {}", socket_path);
+                *lock_channel() = Some(CachedChannel {
+                    pid: std::process::id(),
+                    channel: ch.clone(),
+                });
+                return Ok(ch);
+            }
+            Err(err) => {
+                let pid = peer_pid.load(Ordering::Relaxed);
+                warn!(
+                    "could not connect to authd: socket={} errno={} peer_pid={} error={}",
+                    socket_path,
+                    errno(&err).map_or("-".to_string(), |e| e.to_string()),
+                    if pid > 0 {
+                        pid.to_string()
+                    } else {
+                        "-".to_string()
+                    },
+                    err
+                );
+                last_err = Some(if is_timeout(&err) {
+                    ClientError::ConnectTimeout(socket_path.clone())
+                } else {
+                    ClientError::Transport(socket_path.clone(), err)
+                });
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or(ClientError::SocketMissing(socket_paths)))
+}
+
+/// is_restarting returns true if authd signals that it is restarting, by creating the file returned by
+/// restart_sentinel_path for the duration of the restart.
+pub fn is_restarting() -> bool {
+    Path::new(&super::restart_sentinel_path()).exists()
+}
+
+/// connect_slots returns the semaphore bounding the connections to authd being established at the same
+/// time by the process, which smooths the bursts of connections of multi-threaded processes.
+///
+/// The limit can be set with AUTHD_NSS_MAX_CONCURRENT_CONNECTS and defaults to
+/// DEFAULT_MAX_CONCURRENT_CONNECTS. A slot is only held while connecting, which is bounded by the
+/// connection timeout, or by the request timeout while authd is restarting.
+fn connect_slots() -> &'static Semaphore {
+    CONNECT_SLOTS.get_or_init(|| {
+        let max = super::setting("AUTHD_NSS_MAX_CONCURRENT_CONNECTS")
+            .ok()
+            .and_then(|v| v.trim().parse::<usize>().ok())
+            .filter(|max| *max > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_CONNECTS);
+        Semaphore::new(max)
+    })
+}
+
+/// cached_channel returns the connection to authd established by a previous lookup, if it can still
+/// be used.
+///
+/// tonic reconnects the channel by itself when the socket is closed, but the channel is unusable once
+/// the task driving it is gone, in which case it is dropped so that a new one is established.
+async fn cached_channel() -> Option<Channel> {
+    let mut ch = {
+        let mut cached = lock_channel();
+        match cached.as_ref() {
+            Some(c) if c.pid == std::process::id() => c.channel.clone(),
+            Some(_) => {
+                // The channel of the parent is driven by the runtime of the parent, which can't be used by
+                // the child. Dropping it would wake up the tasks of that runtime, so it's leaked instead.
+                mem::forget(cached.take());
+                return None;
+            }
+            None => return None,
+        }
+    };
+
+    if let Err(err) = ServiceExt::<http::Request<BoxBody>>::ready(&mut ch).await {
+        debug!("Connection to authd is broken ({}), reconnecting...", err);
+        forget_channel();
+        return None;
     }
+    Some(ch)
+}
+
+/// forget_channel drops the connection to authd, so that the next lookup establishes a new one.
+fn forget_channel() {
+    *lock_channel() = None;
+}
 
-    debug!("Connecting to authd on {}...", super::socket_path());
+/// lock_channel locks the connection to authd. A panic while holding the lock can't leave the cached
+/// channel in an inconsistent state, so the lock is recovered if it was poisoned.
+fn lock_channel() -> std::sync::MutexGuard<'static, Option<CachedChannel>> {
+    CHANNEL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// connect creates a channel to authd listening on socket_path. Once the socket is connected, the pid of
+/// the process listening on it is stored in peer_pid.
+async fn connect(
+    socket_path: String,
+    peer_pid: Arc<AtomicI32>,
+) -> Result<Channel, tonic::transport::Error> {
+    debug!("Connecting to authd on {}...", socket_path);
 
     // The URL must have a valid format, even though we don't use it.
     let ch = Endpoint::try_from("https://not-used:404")?
-        .connect_with_connector(service_fn(|_: Uri| {
-            UnixStream::connect(super::socket_path())
+        .user_agent(USER_AGENT)?
+        .connect_timeout(super::connection_timeout())
+        .timeout(super::request_timeout())
+        .connect_with_connector(service_fn(move |_: Uri| {
+            let path = socket_path.clone();
+            let peer_pid = peer_pid.clone();
+            async move {
+                let stream = connect_socket(path).await?;
+                if let Ok(Some(pid)) = stream.peer_cred().map(|c| c.pid()) {
+                    peer_pid.store(pid, Ordering::Relaxed);
+                }
+                Ok::<_, io::Error>(stream)
+            }
         }))
         .await?;
 
-    Ok(NssClient::new(ch))
+    Ok(ch)
+}
+
+/// is_timeout returns true if the connection failed because it was not established in time.
+fn is_timeout(err: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut)
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// errno returns the OS error code at the origin of err, if any.
+fn errno(err: &(dyn Error + 'static)) -> Option<i32> {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if let Some(code) = e
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error)
+        {
+            return Some(code);
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// is_bypassed returns true if the lookups must not reach authd because they are performed by systemd
+/// on behalf of dbus.
+pub fn is_bypassed() -> bool {
+    std::env::var("SYSTEMD_NSS_DYNAMIC_BYPASS").is_ok()
+}
+
+/// is_socket_missing returns true if there is no socket file at path. Abstract sockets, whose path starts
+/// with '@', don't exist on the filesystem, so they are never reported as missing.
+pub fn is_socket_missing(path: &str) -> bool {
+    !path.starts_with('@') && !Path::new(path).exists()
+}
+
+/// connect_socket connects to the unix socket at path, retrying when the attempt is interrupted by a
+/// signal received by the calling process.
+///
+/// The retries don't need their own bound, as the connector is already limited by the connection
+/// timeout.
+async fn connect_socket(path: String) -> io::Result<UnixStream> {
+    loop {
+        let result = match path.strip_prefix('@') {
+            Some(name) => connect_abstract(name),
+            None => UnixStream::connect(&path).await,
+        };
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                debug!("Connection to {} interrupted, retrying...", path);
+            }
+            r => return r,
+        }
+    }
+}
+
+/// connect_abstract connects to the abstract unix socket named name, without its leading '@'.
+///
+/// Like for the sockets on the filesystem, the connection doesn't block: it fails right away if the
+/// daemon has too many connections waiting to be accepted.
+///
+/// Any user can create an abstract socket, so the connection is refused unless the socket was created by
+/// root or by the effective user of the process.
+fn connect_abstract(name: &str) -> io::Result<UnixStream> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // The name of an abstract socket starts after a null byte, and is not null terminated.
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("abstract socket name is too long: {}", name),
+        ));
+    }
+    for (dst, src) in addr.sun_path[1..].iter_mut().zip(name.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let addr_ptr = &addr as *const libc::sockaddr_un as *const libc::sockaddr;
+    if unsafe { libc::connect(fd.as_raw_fd(), addr_ptr, len as libc::socklen_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let stream = UnixStream::from_std(std::os::unix::net::UnixStream::from(fd))?;
+    let uid = stream.peer_cred()?.uid();
+    if uid != 0 && uid != unsafe { libc::geteuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "abstract socket {} is not owned by root or the current user: owned by uid {}",
+                name, uid
+            ),
+        ));
+    }
+
+    Ok(stream)
+}
+
+/// call_with_retry performs the request made by f, retrying it up to MAX_ATTEMPTS times in total if it
+/// fails with a transient error. All the attempts, and the delays between them, fit in the request
+/// timeout.
+///
+/// If authd answers that it can't handle the request for now and suggests a delay before retrying, that
+/// delay is used, up to MAX_RETRY_DELAY. If the connection to authd is broken, for instance because the
+/// daemon restarted, or authd didn't answer in time, the request is retried after a backoff starting at
+/// RETRY_BACKOFF, which makes the channel reconnect to the socket. Other errors, including the ones
+/// returned by authd as Unknown, are returned right away as retrying them may not be safe.
+///
+/// While authd is restarting, the request is retried every RESTART_POLL_INTERVAL until it succeeds or the
+/// request timeout expires, so that the lookups survive a quick restart.
+pub async fn call_with_retry<T, F, Fut>(mut f: F) -> Result<T, Status>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Response<T>, Status>>,
+{
+    let deadline = tokio::time::Instant::now() + super::request_timeout();
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let status = match timed(tokio::time::timeout_at(deadline, f())).await {
+            Ok(Ok(r)) => return Ok(r.into_inner()),
+            Ok(Err(status)) => status,
+            Err(_) => return Err(Status::deadline_exceeded("request timed out")),
+        };
+        // Losing the connection while authd is restarting is expected: the request is held until authd
+        // is back, without counting the attempts.
+        let restarting = is_connection_error(&status) && is_restarting();
+        if attempt >= MAX_ATTEMPTS && !restarting {
+            return Err(status);
+        }
+
+        let delay = match retry_delay(&status) {
+            Some(delay) => delay,
+            None if restarting => {
+                forget_channel();
+                RESTART_POLL_INTERVAL
+            }
+            None if is_connection_error(&status) => {
+                forget_channel();
+                backoff
+            }
+            None if status.code() == Code::DeadlineExceeded => backoff,
+            None => return Err(status),
+        };
+        if tokio::time::Instant::now() + delay >= deadline {
+            return Err(status);
+        }
+
+        debug!(
+            "Request failed with a transient error ({}), retrying in {:?}",
+            status.message(),
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        if !restarting {
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// timed waits for the request fut and records how long authd took to answer it, if the metrics
+/// feature is enabled.
+async fn timed<T>(fut: impl Future<Output = T>) -> T {
+    #[cfg(feature = "metrics")]
+    let start = std::time::Instant::now();
+    let r = fut.await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_request(start.elapsed());
+    r
+}
+
+/// is_connection_error returns true if the request failed because the connection to authd was lost
+/// rather than because of an error returned by authd.
+///
+/// authd returns its own errors as Unknown, so the code alone doesn't tell them apart from a broken
+/// connection: only the statuses built by the client carry the transport error which caused them.
+/// Unavailable is also used when authd can't be reached, unless authd sent it with a retry delay.
+fn is_connection_error(status: &Status) -> bool {
+    if status.code() == Code::Unavailable && retry_delay(status).is_none() {
+        return true;
+    }
+
+    let mut source = status.source();
+    while let Some(e) = source {
+        if e.is::<tonic::transport::Error>() || e.is::<hyper::Error>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// retry_delay returns the delay suggested by authd before retrying a request that failed with status,
+/// if any. The delay is capped to MAX_RETRY_DELAY, so that a lookup is not held for long by authd.
+fn retry_delay(status: &Status) -> Option<Duration> {
+    if !matches!(status.code(), Code::ResourceExhausted | Code::Unavailable) {
+        return None;
+    }
+
+    let details = RpcStatus::decode(status.details()).ok()?;
+    let retry_info = details
+        .details
+        .iter()
+        .find(|d| d.type_url == RETRY_INFO_TYPE_URL)?;
+    let delay = RetryInfo::decode(retry_info.value.as_slice())
+        .ok()?
+        .retry_delay?;
+
+    let delay = Duration::new(
+        u64::try_from(delay.seconds).ok()?,
+        u32::try_from(delay.nanos).ok()?,
+    );
+    Some(delay.min(MAX_RETRY_DELAY))
+}
+
+/// authd_nss_cancel_lookups cancels the lookups in progress in the process, which then return Unavail
+/// right away instead of waiting for authd until the request timeout.
+///
+/// It takes a lock, so it must not be called from a signal handler. Processes cancelling their lookups
+/// on a signal can call it from the thread handling the signals, with sigwait or signalfd.
+#[no_mangle]
+pub extern "C" fn authd_nss_cancel_lookups() {
+    CANCELLED.notify_waiters();
+}
+
+/// cancellable runs the lookup fut until it completes, or returns Unavail if authd_nss_cancel_lookups
+/// is called in the meantime.
+pub async fn cancellable<T>(fut: impl Future<Output = NssResponse<T>>) -> NssResponse<T> {
+    // The lookup is registered right away, so that it's cancelled even if it didn't start yet.
+    let cancelled = CANCELLED.notified();
+
+    tokio::select! {
+        r = fut => r,
+        _ = cancelled => {
+            debug!("Lookup cancelled");
+            NssResponse::Unavail
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::linux::net::SocketAddrExt;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    /// RUNTIME_TESTS serializes the tests forking the process or using its runtime, so that the forked
+    /// children don't inherit a lock held by another test.
+    static RUNTIME_TESTS: Mutex<()> = Mutex::new(());
+
+    /// in_child runs f in a forked child and returns whether it succeeded.
+    fn in_child(f: impl FnOnce() -> bool) -> bool {
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+            0 => {
+                let ok = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or(false);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+
+    #[test]
+    fn lookups_share_the_runtime() {
+        let _lock = RUNTIME_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let want = runtime().unwrap() as *const Runtime as usize;
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| thread::spawn(|| runtime().unwrap() as *const Runtime as usize))
+            .collect();
+
+        for t in threads {
+            assert_eq!(
+                t.join().unwrap(),
+                want,
+                "lookups of all the threads should use the same runtime"
+            );
+        }
+    }
+
+    #[test]
+    fn forked_child_runs_lookups_on_its_own_runtime() {
+        let _lock = RUNTIME_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let parent: *const Runtime = runtime().unwrap();
+
+        let ok = in_child(|| {
+            let rt = runtime().unwrap();
+            let r = rt.block_on(async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                42
+            });
+            !std::ptr::eq(rt, parent) && r == 42
+        });
+
+        assert!(ok, "child should run the lookups on a fresh runtime");
+    }
+
+    #[test]
+    fn forked_children_build_at_most_one_runtime() {
+        let _lock = RUNTIME_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let parent: *const Runtime = runtime().unwrap();
+
+        for _ in 0..10 {
+            let ok = in_child(|| {
+                let first: *const Runtime = runtime().unwrap();
+                let second: *const Runtime = runtime().unwrap();
+                first != parent && first == second
+            });
+            assert!(ok, "child should build a single runtime of its own");
+        }
+
+        let after: *const Runtime = runtime().unwrap();
+        assert_eq!(after, parent, "parent should keep its runtime");
+    }
+
+    #[test]
+    fn connect_socket_connects_to_abstract_sockets() {
+        let name = format!("authd-nss-tests-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let listener = std::os::unix::net::UnixListener::bind_addr(&addr).unwrap();
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+
+        let r = rt.block_on(connect_socket(format!("@{}", name)));
+
+        assert!(r.is_ok(), "connection should succeed: {:?}", r.err());
+        assert!(
+            listener.accept().is_ok(),
+            "listener should accept the connection"
+        );
+        assert!(
+            !is_socket_missing(&format!("@{}", name)),
+            "abstract socket should not be reported as missing"
+        );
+    }
+
+    #[test]
+    fn connect_socket_fails_when_nothing_listens_on_the_abstract_socket() {
+        let name = format!("authd-nss-tests-missing-{}", std::process::id());
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+
+        let r = rt.block_on(connect_socket(format!("@{}", name)));
+
+        assert_eq!(
+            r.err().map(|e| e.kind()),
+            Some(io::ErrorKind::ConnectionRefused),
+            "connection should be refused"
+        );
+    }
+
+    /// status_with_details returns a status with the given code, carrying the given details.
+    fn status_with_details(code: Code, details: Vec<Any>) -> Status {
+        let details = RpcStatus {
+            code: code as i32,
+            message: String::new(),
+            details,
+        };
+        Status::with_details(
+            code,
+            "authd is busy",
+            tonic::codegen::Bytes::from(details.encode_to_vec()),
+        )
+    }
+
+    /// retry_info returns the details suggesting to retry after the given delay.
+    fn retry_info(seconds: i64, nanos: i32) -> Any {
+        Any {
+            type_url: RETRY_INFO_TYPE_URL.to_string(),
+            value: RetryInfo {
+                retry_delay: Some(ProtoDuration { seconds, nanos }),
+            }
+            .encode_to_vec(),
+        }
+    }
+
+    #[test]
+    fn retry_delay_returns_the_delay_suggested_by_authd() {
+        let tests = [
+            (
+                Code::Unavailable,
+                retry_info(0, 200_000_000),
+                Duration::from_millis(200),
+            ),
+            (
+                Code::ResourceExhausted,
+                retry_info(0, 10_000_000),
+                Duration::from_millis(10),
+            ),
+            (Code::Unavailable, retry_info(3600, 0), MAX_RETRY_DELAY),
+            (Code::Unavailable, retry_info(1, 1), MAX_RETRY_DELAY),
+        ];
+
+        for (code, details, want) in tests {
+            let got = retry_delay(&status_with_details(code, vec![details]));
+            assert_eq!(got, Some(want), "unexpected delay for code {:?}", code);
+        }
+    }
+
+    #[test]
+    fn retry_delay_ignores_invalid_details() {
+        let other = Any {
+            type_url: "type.googleapis.com/google.rpc.DebugInfo".to_string(),
+            value: vec![],
+        };
+        let malformed = Any {
+            type_url: RETRY_INFO_TYPE_URL.to_string(),
+            value: vec![0x0a, 0x05, 0x08],
+        };
+        let tests = [
+            ("no details", Status::unavailable("authd is busy")),
+            (
+                "details that are not a status",
+                Status::with_details(
+                    Code::Unavailable,
+                    "authd is busy",
+                    tonic::codegen::Bytes::from_static(&[0xff, 0xff, 0xff]),
+                ),
+            ),
+            (
+                "no retry info",
+                status_with_details(Code::Unavailable, vec![other]),
+            ),
+            (
+                "malformed retry info",
+                status_with_details(Code::Unavailable, vec![malformed]),
+            ),
+            (
+                "no delay",
+                status_with_details(
+                    Code::Unavailable,
+                    vec![Any {
+                        type_url: RETRY_INFO_TYPE_URL.to_string(),
+                        value: RetryInfo { retry_delay: None }.encode_to_vec(),
+                    }],
+                ),
+            ),
+            (
+                "negative delay",
+                status_with_details(Code::Unavailable, vec![retry_info(-1, 0)]),
+            ),
+            (
+                "not a transient error",
+                status_with_details(Code::NotFound, vec![retry_info(0, 200_000_000)]),
+            ),
+        ];
+
+        for (name, status) in tests {
+            assert_eq!(
+                retry_delay(&status),
+                None,
+                "{}: no delay should be returned",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn call_with_retry_waits_for_the_delay_suggested_by_authd() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let mut calls = 0;
+
+        let start = Instant::now();
+        let r = rt.block_on(call_with_retry(|| {
+            calls += 1;
+            let r = if calls == 1 {
+                Err(status_with_details(
+                    Code::Unavailable,
+                    vec![retry_info(0, 300_000_000)],
+                ))
+            } else {
+                Ok(Response::new(42))
+            };
+            async move { r }
+        }));
+
+        assert_eq!(r.ok(), Some(42), "request should succeed once retried");
+        assert_eq!(calls, 2, "request should be retried once");
+        assert!(
+            start.elapsed() >= Duration::from_millis(300),
+            "request should be retried after the suggested delay, not {:?}",
+            start.elapsed()
+        );
+    }
+
+    /// transport_error returns the error of a connection to authd refused by the socket.
+    fn transport_error() -> tonic::transport::Error {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            Endpoint::try_from("https://not-used:404")
+                .unwrap()
+                .connect_with_connector(service_fn(|_: Uri| async {
+                    Err::<UnixStream, _>(io::Error::from_raw_os_error(libc::ECONNREFUSED))
+                }))
+                .await
+                .unwrap_err()
+        })
+    }
+
+    #[test]
+    fn client_error_describes_the_connection_failure() {
+        let socket = "/run/authd.sock".to_string();
+        let tests = [
+            (
+                ClientError::Bypassed,
+                "NSS lookup performed through systemd, skipping...".to_string(),
+            ),
+            (
+                ClientError::SocketMissing(vec![socket.clone(), "@authd".to_string()]),
+                "none of the sockets /run/authd.sock, @authd exist, is authd running?".to_string(),
+            ),
+            (
+                ClientError::ConnectTimeout(socket.clone()),
+                "timed out connecting to authd on /run/authd.sock".to_string(),
+            ),
+            (
+                ClientError::Transport(socket, transport_error()),
+                format!(
+                    "could not connect to authd on /run/authd.sock: {}",
+                    transport_error()
+                ),
+            ),
+        ];
+
+        for (err, want) in tests {
+            assert_eq!(err.to_string(), want, "unexpected message for {:?}", err);
+        }
+    }
+
+    #[test]
+    fn client_error_keeps_the_transport_error_as_source() {
+        let err = ClientError::Transport("/run/authd.sock".to_string(), transport_error());
+
+        let source = err.source().expect("transport error should be the source");
+        assert_eq!(
+            errno(source),
+            Some(libc::ECONNREFUSED),
+            "errno of the connection should be found"
+        );
+        assert!(!is_timeout(source), "refused connection is not a timeout");
+
+        for err in [
+            ClientError::Bypassed,
+            ClientError::SocketMissing(vec![]),
+            ClientError::ConnectTimeout("/run/authd.sock".to_string()),
+        ] {
+            assert!(err.source().is_none(), "{:?} should have no source", err);
+        }
+    }
+
+    /// CHANNEL_TESTS serializes the tests using the cached channel.
+    static CHANNEL_TESTS: Mutex<()> = Mutex::new(());
+
+    /// cache_lazy_channel caches a channel which only connects to authd when it's used.
+    fn cache_lazy_channel() {
+        let channel = Endpoint::try_from("https://not-used:404")
+            .unwrap()
+            .connect_with_connector_lazy(service_fn(|_: Uri| async {
+                Err::<UnixStream, _>(io::Error::from_raw_os_error(libc::ECONNREFUSED))
+            }));
+        *lock_channel() = Some(CachedChannel {
+            pid: std::process::id(),
+            channel,
+        });
+    }
+
+    #[test]
+    fn call_with_retry_returns_the_errors_of_authd_right_away() {
+        let _lock = CHANNEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let mut calls = 0;
+
+        let r = rt.block_on(async {
+            cache_lazy_channel();
+            call_with_retry(|| {
+                calls += 1;
+                async { Err::<Response<()>, _>(Status::unknown("cache is corrupted")) }
+            })
+            .await
+        });
+
+        assert_eq!(r.map_err(|s| s.code()), Err(Code::Unknown));
+        assert_eq!(calls, 1, "errors of authd should not be retried");
+        assert!(
+            lock_channel().take().is_some(),
+            "connection should be kept after an error of authd"
+        );
+    }
+
+    #[test]
+    fn call_with_retry_reconnects_when_the_connection_is_broken() {
+        let _lock = CHANNEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+        let mut broken = Status::unknown("transport error");
+        broken.set_source(Arc::new(transport_error()));
+        let mut calls = 0;
+
+        let r = rt.block_on(async {
+            cache_lazy_channel();
+            call_with_retry(|| {
+                calls += 1;
+                let r = if calls == 1 {
+                    Err(broken.clone())
+                } else {
+                    Ok(Response::new(42))
+                };
+                async move { r }
+            })
+            .await
+        });
+
+        assert_eq!(r.ok(), Some(42), "request should succeed once retried");
+        assert_eq!(calls, 2, "request should be retried once");
+        assert!(
+            lock_channel().is_none(),
+            "broken connection should be forgotten"
+        );
+    }
+
+    #[test]
+    fn cancellable_returns_unavail_when_cancelled() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+
+        // Lookups are only cancelled once they started, so keep cancelling until the lookup returns.
+        let done = Arc::new(AtomicBool::new(false));
+        let canceller = {
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(10));
+                    authd_nss_cancel_lookups();
+                }
+            })
+        };
+
+        let start = Instant::now();
+        let r = rt.block_on(cancellable(async {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            NssResponse::Success(())
+        }));
+        done.store(true, Ordering::SeqCst);
+        canceller.join().unwrap();
+
+        assert!(
+            matches!(r, NssResponse::Unavail),
+            "lookup should be unavailable"
+        );
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "lookup should return promptly once cancelled, took {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn cancellable_returns_the_response_of_the_lookup() {
+        let rt = Builder::new_current_thread().enable_all().build().unwrap();
+
+        let r = rt.block_on(cancellable(async { NssResponse::Success(42) }));
+
+        assert!(
+            matches!(r, NssResponse::Success(42)),
+            "lookup should succeed"
+        );
+    }
 }
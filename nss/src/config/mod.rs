@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::{debug, error};
+
+/// CONFIG_FILE is the file holding the settings of the module set by the administrator.
+const CONFIG_FILE: &str = "/etc/authd/nss.conf";
+
+/// SETTINGS holds the settings of the config file, once read.
+static SETTINGS: OnceLock<BTreeMap<String, String>> = OnceLock::new();
+
+/// get returns the value of the setting var in the config file, if it's set there.
+///
+/// Each line of the file sets a setting, like "AUTHD_NSS_DENY_USERS=root,admin". Empty lines and lines
+/// starting with # are ignored. The file is read once per process, and only if it can be trusted.
+pub fn get(var: &str) -> Option<String> {
+    SETTINGS.get_or_init(load).get(var).cloned()
+}
+
+/// config_file returns the path of the config file.
+///
+/// It uses the AUTHD_NSS_CONFIG_FILE env value if set and the integration_tests feature is enabled,
+/// otherwise it uses the default path.
+fn config_file() -> PathBuf {
+    #[cfg(feature = "integration_tests")]
+    if let Ok(path) = std::env::var("AUTHD_NSS_CONFIG_FILE") {
+        return PathBuf::from(path);
+    }
+    PathBuf::from(CONFIG_FILE)
+}
+
+/// load reads the settings of the config file, if it exists and can be trusted.
+fn load() -> BTreeMap<String, String> {
+    let path = config_file();
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(err) => {
+            error!("could not read config file {}: {}", path.display(), err);
+            return BTreeMap::new();
+        }
+    };
+    if !is_trusted_file(&path) {
+        error!(
+            "ignoring config file {}: it must be owned by root and only writable by its owner",
+            path.display()
+        );
+        return BTreeMap::new();
+    }
+
+    debug!("Reading settings from {}", path.display());
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| match l.split_once('=') {
+            Some((var, value)) => Some((var.trim().to_string(), value.trim().to_string())),
+            None => {
+                error!("ignoring invalid line in config file: {:?}", l);
+                None
+            }
+        })
+        .collect()
+}
+
+/// is_trusted_file returns true if the file at path is owned by root and can't be modified by other
+/// users, so that its content can be relied on by any process, setuid programs included.
+///
+/// With the integration_tests feature, the files owned by the effective user are trusted too, so that the
+/// tests don't need to run as root.
+pub fn is_trusted_file(path: &Path) -> bool {
+    let m = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+
+    #[cfg(feature = "integration_tests")]
+    let is_trusted_owner = m.uid() == 0 || m.uid() == unsafe { libc::geteuid() };
+    #[cfg(not(feature = "integration_tests"))]
+    let is_trusted_owner = m.uid() == 0;
+
+    is_trusted_owner && m.mode() & 0o022 == 0
+}
@@ -0,0 +1,208 @@
+use libc::uid_t;
+use libnss::passwd::Passwd;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::debug;
+
+/// FALLBACK_DIR is the directory where successfully resolved passwd entries are stored.
+const FALLBACK_DIR: &str = "/var/lib/authd/nss-cache";
+
+/// FIELD_SEPARATOR separates the fields of a stored entry. Gecos can contain new lines, so we can't
+/// rely on the usual passwd format.
+const FIELD_SEPARATOR: char = '\0';
+
+/// max_age returns for how long a stored entry can be used when authd is not available.
+///
+/// The fallback is disabled by default and is only enabled when AUTHD_NSS_FALLBACK_MAX_AGE is set to
+/// a positive number of seconds. Setuid programs only store and use entries if it's set in the config
+/// file.
+///
+/// Enabling it is a tradeoff between availability and freshness: a user that was removed or modified
+/// on the broker side will still resolve to its last known entry until it expires, as long as authd
+/// can't be reached.
+fn max_age() -> Option<Duration> {
+    let secs = crate::setting("AUTHD_NSS_FALLBACK_MAX_AGE")
+        .ok()?
+        .parse::<u64>()
+        .ok()?;
+    if secs == 0 {
+        return None;
+    }
+    Some(Duration::from_secs(secs))
+}
+
+/// fallback_dir returns the directory used to store the entries.
+///
+/// It uses the AUTHD_NSS_FALLBACK_DIR env value if set and the integration_tests feature is enabled,
+/// otherwise it uses the default path.
+fn fallback_dir() -> PathBuf {
+    #[cfg(feature = "integration_tests")]
+    if let Ok(dir) = std::env::var("AUTHD_NSS_FALLBACK_DIR") {
+        return PathBuf::from(dir);
+    }
+    PathBuf::from(FALLBACK_DIR)
+}
+
+/// store_passwd writes the passwd entry to disk, so that it can be used if authd becomes unavailable.
+///
+/// Only processes allowed to write in the fallback directory (usually root) will store entries.
+/// Any failure is only logged, as the fallback is a best effort mechanism.
+pub fn store_passwd(entry: &Passwd) {
+    if max_age().is_none() || !is_valid_name(&entry.name) {
+        return;
+    }
+
+    let content = [
+        entry.name.as_str(),
+        entry.passwd.as_str(),
+        &entry.uid.to_string(),
+        &entry.gid.to_string(),
+        entry.gecos.as_str(),
+        entry.dir.as_str(),
+        entry.shell.as_str(),
+    ]
+    .join(&FIELD_SEPARATOR.to_string());
+
+    let dir = fallback_dir();
+    for path in [
+        dir.join(format!("passwd-uid-{}", entry.uid)),
+        dir.join(format!("passwd-name-{}", entry.name)),
+    ] {
+        if let Err(err) = write_atomically(&dir, &path, content.as_bytes()) {
+            debug!("could not store fallback entry {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// passwd_by_uid returns the stored passwd entry for the given uid, if it is not older than the max age.
+pub fn passwd_by_uid(uid: uid_t) -> Option<Passwd> {
+    load_passwd(&fallback_dir().join(format!("passwd-uid-{}", uid)))
+}
+
+/// passwd_by_name returns the stored passwd entry for the given name, if it is not older than the max age.
+pub fn passwd_by_name(name: &str) -> Option<Passwd> {
+    if !is_valid_name(name) {
+        return None;
+    }
+    load_passwd(&fallback_dir().join(format!("passwd-name-{}", name)))
+}
+
+/// load_passwd reads and parses the passwd entry stored in path, ignoring it if it has expired.
+fn load_passwd(path: &Path) -> Option<Passwd> {
+    let max_age = max_age()?;
+
+    let age = fs::metadata(path).ok()?.modified().ok()?.elapsed().ok()?;
+    if age > max_age {
+        debug!(
+            "fallback entry {} is too old ({}s), ignoring it",
+            path.display(),
+            age.as_secs()
+        );
+        return None;
+    }
+
+    let content = fs::read_to_string(path).ok()?;
+    let fields: Vec<&str> = content.split(FIELD_SEPARATOR).collect();
+    if fields.len() != 7 {
+        debug!(
+            "fallback entry {} is malformed, ignoring it",
+            path.display()
+        );
+        return None;
+    }
+
+    debug!("using fallback entry {}", path.display());
+    Some(Passwd {
+        name: fields[0].to_string(),
+        passwd: fields[1].to_string(),
+        uid: fields[2].parse().ok()?,
+        gid: fields[3].parse().ok()?,
+        gecos: fields[4].to_string(),
+        dir: fields[5].to_string(),
+        shell: fields[6].to_string(),
+    })
+}
+
+/// TMP_COUNTER numbers the temporary files created by the process, so that the concurrent writes of its
+/// threads never share one.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// write_atomically writes content to path through a temporary file, so that readers never see a
+/// partially written entry.
+///
+/// The temporary file is named after the process, the thread and a counter, and is only used if it's
+/// created by this call: another writer, in this process or not, can never write to it at the same time.
+fn write_atomically(dir: &Path, path: &Path, content: &[u8]) -> std::io::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(dir)?;
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = dir.join(format!(
+        ".{}.tmp-{}-{}-{}",
+        file_name,
+        std::process::id(),
+        unsafe { libc::syscall(libc::SYS_gettid) },
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o644)
+        .open(&tmp)?;
+
+    let result = f
+        .set_permissions(fs::Permissions::from_mode(0o644))
+        .and_then(|_| f.write_all(content))
+        .and_then(|_| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// is_valid_name checks that the name can be safely used as part of a file name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.contains(FIELD_SEPARATOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn write_atomically_supports_concurrent_writers() {
+        let dir =
+            std::env::temp_dir().join(format!("authd-nss-tests-fallback-{}", std::process::id()));
+        let path = dir.join("passwd-name-user1");
+
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let (dir, path) = (dir.clone(), path.clone());
+                thread::spawn(move || {
+                    (0..20).all(|_| {
+                        write_atomically(&dir, &path, format!("entry {}", i).as_bytes()).is_ok()
+                    })
+                })
+            })
+            .collect();
+        let ok = writers.into_iter().all(|w| w.join().unwrap());
+        let content = fs::read_to_string(&path);
+        let files = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(ok, "concurrent writes should all succeed");
+        assert!(
+            content.is_ok_and(|c| c.starts_with("entry ")),
+            "entry should be written by one of the writers"
+        );
+        assert_eq!(files, 1, "no temporary file should be left behind");
+    }
+}
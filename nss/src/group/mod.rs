@@ -1,34 +1,67 @@
-use crate::error;
+use crate::{debug, error, logs};
 use libc::gid_t;
 use libnss::group::{Group, GroupHooks};
 use libnss::interop::Response;
-use tokio::runtime::Builder;
-use tonic::Request;
 
 use crate::client::{self, authd};
+use crate::{cache, names, policy, snapshot};
 use authd::GroupEntry;
 
 pub struct AuthdGroup;
 impl GroupHooks for AuthdGroup {
     /// get_all_entries returns all group entries.
     fn get_all_entries() -> Response<Vec<Group>> {
-        get_all_entries()
+        logs::lookup("group_entries", "all", || {
+            if !policy::is_enumeration_enabled() {
+                return Response::Success(vec![]);
+            }
+            let r = snapshot::answer(|s| {
+                Response::Success(s.group.into_iter().filter(is_valid_group).collect())
+            })
+            .unwrap_or_else(|| {
+                cache::cached(&cache::GROUP_ENTRIES, "group_entries", get_all_entries)
+            });
+            policy::limit_enumeration("group", r)
+        })
     }
 
     /// get_entry_by_gid returns the group entry for the given gid.
     fn get_entry_by_gid(gid: gid_t) -> Response<Group> {
-        get_entry_by_gid(gid)
+        logs::lookup("group_by_gid", &gid.to_string(), || {
+            if let Some(r) = snapshot::answer(|s| valid(snapshot::find(s.group, |g| g.gid == gid)))
+            {
+                return r;
+            }
+            with_primary_group(
+                cache::cached(&cache::GROUP, &format!("group_by_gid:{}", gid), || {
+                    get_entry_by_gid(gid)
+                }),
+                |g| g.gid == gid,
+            )
+        })
     }
 
     /// get_entry_by_name returns the group entry for the given name.
     fn get_entry_by_name(name: String) -> Response<Group> {
-        get_entry_by_name(name)
+        logs::lookup("group_by_name", &name.clone(), || {
+            if let Some(r) =
+                snapshot::answer(|s| valid(snapshot::find(s.group, |g| g.name == name)))
+            {
+                return r;
+            }
+            with_primary_group(
+                cache::cached(&cache::GROUP, &format!("group_by_name:{}", name), || {
+                    names::by_name(&name, get_entry_by_name)
+                }),
+                |g| g.name == name,
+            )
+        })
     }
 }
 
 /// get_all_entries connects to the grpc server and asks for all group entries.
-fn get_all_entries() -> Response<Vec<Group>> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+pub fn get_all_entries() -> Response<Vec<Group>> {
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -36,8 +69,8 @@ fn get_all_entries() -> Response<Vec<Group>> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -45,20 +78,26 @@ fn get_all_entries() -> Response<Vec<Group>> {
             }
         };
 
-        let request = Request::new(authd::Empty {});
-        match client.get_group_entries(request).await {
-            Ok(r) => Response::Success(group_entries_to_groups(r.into_inner().entries)),
+        let req = authd::Empty {};
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_group_entries(req).await }
+        })
+        .await
+        {
+            Ok(r) => Response::Success(group_entries_to_groups(r.entries)),
             Err(e) => {
                 error!("error when listing groups: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
 }
 
 /// get_entry_by_gid connects to the grpc server and asks for the group entry with the given gid.
 fn get_entry_by_gid(gid: gid_t) -> Response<Group> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -66,8 +105,8 @@ fn get_entry_by_gid(gid: gid_t) -> Response<Group> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -75,20 +114,26 @@ fn get_entry_by_gid(gid: gid_t) -> Response<Group> {
             }
         };
 
-        let req = Request::new(authd::GetByIdRequest { id: gid });
-        match client.get_group_by_gid(req).await {
-            Ok(r) => Response::Success(group_entry_to_group(r.into_inner())),
+        let req = authd::GetByIdRequest { id: gid };
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_group_by_gid(req).await }
+        })
+        .await
+        {
+            Ok(r) => valid_group(group_entry_to_group(r)),
             Err(e) => {
                 error!("error when getting group by gid: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
 }
 
 /// get_entry_by_name connects to the grpc server and asks for the group entry with the given name.
 fn get_entry_by_name(name: String) -> Response<Group> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -96,8 +141,8 @@ fn get_entry_by_name(name: String) -> Response<Group> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -105,28 +150,162 @@ fn get_entry_by_name(name: String) -> Response<Group> {
             }
         };
 
-        let req = Request::new(authd::GetByNameRequest { name });
-        match client.get_group_by_name(req).await {
-            Ok(r) => Response::Success(group_entry_to_group(r.into_inner())),
+        let req = authd::GetByNameRequest { name };
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_group_by_name(req).await }
+        })
+        .await
+        {
+            Ok(r) => valid_group(group_entry_to_group(r)),
             Err(e) => {
                 error!("error when getting group by name: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
+}
+
+/// with_primary_group answers with a group synthesized from the primary group of a user, matching
+/// the given predicate, when authd doesn't know the requested group.
+///
+/// This is opt-in through AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS=1, for brokers which only return the
+/// primary group of a user through its passwd gid. It's a heuristic: the synthesized group only has
+/// the user as member, its name comes from AUTHD_NSS_PRIMARY_GROUP_NAME (where "{user}" is replaced
+/// by the user name, and which defaults to the user name) and may collide with a local group, and the
+/// gid may actually belong to a group which was removed from the broker. Setuid programs only
+/// synthesize groups if it's enabled in the config file.
+///
+/// The list of synthesized groups is kept in the enumeration cache, so that looking up several missing
+/// groups doesn't list all the users of authd each time.
+fn with_primary_group(
+    response: Response<Group>,
+    matches: impl Fn(&Group) -> bool,
+) -> Response<Group> {
+    if !matches!(response, Response::NotFound)
+        || crate::setting("AUTHD_NSS_SYNTHESIZE_PRIMARY_GROUPS").as_deref() != Ok("1")
+    {
+        return response;
+    }
+
+    match cache::cached(&cache::GROUP_ENTRIES, "primary_groups", get_primary_groups) {
+        Response::Success(groups) => match groups.into_iter().find(|g| matches(g)) {
+            Some(group) => {
+                debug!("synthesized primary group {} ({})", group.name, group.gid);
+                valid_group(group)
+            }
+            None => Response::NotFound,
+        },
+        Response::Unavail => Response::Unavail,
+        _ => Response::NotFound,
+    }
+}
+
+/// get_primary_groups connects to the grpc server and builds the primary groups of all its users which
+/// the module is allowed to answer.
+fn get_primary_groups() -> Response<Vec<Group>> {
+    let rt = match client::runtime() {
+        Ok(rt) => rt,
+        Err(e) => {
+            error!("could not create runtime for NSS: {}", e);
+            return Response::Unavail;
+        }
+    };
+
+    let template =
+        crate::setting("AUTHD_NSS_PRIMARY_GROUP_NAME").unwrap_or_else(|_| "{user}".to_string());
+
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
+            Ok(c) => c,
+            Err(e) => {
+                error!("could not connect to gRPC server: {}", e);
+                return Response::Unavail;
+            }
+        };
+
+        let req = authd::Empty {};
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_passwd_entries(req).await }
+        })
+        .await
+        {
+            Ok(r) => Response::Success(
+                r.entries
+                    .into_iter()
+                    .map(|u| (names::to_nss(u.name), u.uid, u.gid))
+                    .filter(|(name, uid, _)| {
+                        policy::is_name_allowed(name) && policy::is_uid_allowed(*uid)
+                    })
+                    .map(|(name, _, gid)| Group {
+                        name: template.replace("{user}", &name),
+                        passwd: "x".to_string(),
+                        gid,
+                        members: vec![name],
+                    })
+                    .collect(),
+            ),
+            Err(e) => {
+                error!("error when listing passwd: {}", e.message());
+                super::grpc_status_to_nss_response(e)
+            }
+        }
+    }))
+}
+
+/// valid_group returns the group, unless it is invalid, in which case it returns NotFound.
+fn valid_group(group: Group) -> Response<Group> {
+    if !is_valid_group(&group) {
+        return Response::NotFound;
+    }
+    Response::Success(group)
+}
+
+/// valid returns the response, unless it holds an invalid group.
+fn valid(response: Response<Group>) -> Response<Group> {
+    match response {
+        Response::Success(group) => valid_group(group),
+        r => r,
+    }
+}
+
+/// is_valid_group returns true if the group has a name and its gid is in the allowed range.
+pub fn is_valid_group(group: &Group) -> bool {
+    if group.name.is_empty() {
+        error!(
+            "ignoring group with gid {}: authd returned an empty name",
+            group.gid
+        );
+        return false;
+    }
+    if policy::is_id_in_range(group.gid) {
+        return true;
+    }
+    error!(
+        "ignoring group {}: gid {} is outside of the allowed range",
+        group.name, group.gid
+    );
+    false
 }
 
 /// group_entry_to_group converts a GroupEntry to a libnss::Group.
 fn group_entry_to_group(entry: GroupEntry) -> Group {
     Group {
-        name: entry.name,
+        name: names::to_nss(entry.name),
         passwd: entry.passwd,
         gid: entry.gid,
-        members: entry.members,
+        members: entry.members.into_iter().map(names::to_nss).collect(),
     }
 }
 
 /// group_entries_to_groups converts a Vec<GroupEntry> to a Vec<libnss::Group>.
-fn group_entries_to_groups(entries: Vec<GroupEntry>) -> Vec<Group> {
-    entries.into_iter().map(group_entry_to_group).collect()
+pub fn group_entries_to_groups(entries: Vec<GroupEntry>) -> Vec<Group> {
+    entries
+        .into_iter()
+        .map(group_entry_to_group)
+        .filter(is_valid_group)
+        .collect()
 }
@@ -0,0 +1,143 @@
+use crate::logs;
+use libc::{c_char, c_int, gid_t, size_t, ENOENT, ENOMEM};
+use libnss::group::Group;
+use libnss::initgroups::InitgroupsHooks;
+use libnss::interop::{NssStatus, Response};
+use std::ffi::CStr;
+use std::mem;
+use std::ptr;
+use std::slice;
+
+use crate::group::{self, is_valid_group};
+use crate::{cache, policy, snapshot};
+
+pub struct AuthdInitgroups;
+impl InitgroupsHooks for AuthdInitgroups {
+    /// get_entries_by_user returns the groups the given user is a member of.
+    ///
+    /// _nss_authd_initgroups_dyn skips the group given by the caller, usually the primary group of the
+    /// user, and doesn't return more groups than the limit it was given.
+    fn get_entries_by_user(user: String) -> Response<Vec<Group>> {
+        logs::lookup("initgroups", &user.clone(), || {
+            if !policy::is_name_allowed(&user) {
+                return Response::NotFound;
+            }
+            if let Some(r) = snapshot::answer(|s| {
+                Response::Success(
+                    s.group
+                        .into_iter()
+                        .filter(|g| is_valid_group(g) && g.members.contains(&user))
+                        .collect(),
+                )
+            }) {
+                return r;
+            }
+            get_entries_by_user(user)
+        })
+    }
+}
+
+/// _nss_authd_initgroups_dyn appends the gids of the groups of the user name, except skipgroup, to the
+/// array groupsp of size elements, filled up to start. The array is grown if needed, but never beyond
+/// limit elements.
+///
+/// The groups of a user can come from several brokers, which may use the same gid. Each gid is only
+/// appended once, and not at all if it's already in the array, so that duplicates don't use up the room
+/// left before the limit.
+///
+/// It's implemented here rather than with libnss_initgroups_hooks!, whose hook computes the room left
+/// in the array as limit - start, which underflows when glibc calls it with an array already full.
+///
+/// # Safety
+///
+/// The pointers must be valid as documented for the initgroups_dyn function of NSS modules, and
+/// groupsp must have been allocated with malloc.
+#[no_mangle]
+pub unsafe extern "C" fn _nss_authd_initgroups_dyn(
+    name: *const c_char,
+    skipgroup: gid_t,
+    start: *mut size_t,
+    size: *mut size_t,
+    groupsp: *mut *mut gid_t,
+    limit: size_t,
+    errnop: *mut c_int,
+) -> c_int {
+    let user = match CStr::from_ptr(name).to_str() {
+        Ok(user) => user.to_owned(),
+        Err(_) => {
+            *errnop = ENOENT;
+            return NssStatus::NotFound as c_int;
+        }
+    };
+
+    let groups = match AuthdInitgroups::get_entries_by_user(user) {
+        Response::Success(groups) => groups,
+        r => {
+            *errnop = ENOENT;
+            return r.to_status() as c_int;
+        }
+    };
+
+    let room = limit.saturating_sub(*start);
+    if room == 0 {
+        return NssStatus::Success as c_int;
+    }
+    let existing: &[gid_t] = if (*groupsp).is_null() {
+        &[]
+    } else {
+        slice::from_raw_parts(*groupsp, (*start).min(*size))
+    };
+    let mut gids: Vec<gid_t> = Vec::new();
+    for gid in groups.into_iter().map(|g| g.gid) {
+        if gids.len() == room {
+            break;
+        }
+        if gid != skipgroup && !existing.contains(&gid) && !gids.contains(&gid) {
+            gids.push(gid);
+        }
+    }
+    if gids.is_empty() {
+        return NssStatus::Success as c_int;
+    }
+
+    let new_start = *start + gids.len();
+    if new_start > *size {
+        let grown =
+            libc::realloc((*groupsp).cast(), new_start * mem::size_of::<gid_t>()) as *mut gid_t;
+        if grown.is_null() {
+            *errnop = ENOMEM;
+            return NssStatus::TryAgain as c_int;
+        }
+        *groupsp = grown;
+        *size = new_start;
+    }
+
+    ptr::copy_nonoverlapping(gids.as_ptr(), (*groupsp).add(*start), gids.len());
+    *start = new_start;
+
+    NssStatus::Success as c_int
+}
+
+/// get_entries_by_user returns the groups listed by authd that the given user is a member of.
+///
+/// authd has no request to list the groups of a user, but this avoids glibc falling back to going
+/// through all groups entry by entry to compute them. The listed groups come from all the brokers, so
+/// the user gets the groups of each of them. They are shared with the group enumerations through the
+/// enumeration cache, so that with AUTHD_NSS_ENUMERATION_CACHE_TTL set, the groups are only listed once
+/// per TTL rather than on each call.
+fn get_entries_by_user(user: String) -> Response<Vec<Group>> {
+    let groups = cache::cached(
+        &cache::GROUP_ENTRIES,
+        "group_entries",
+        group::get_all_entries,
+    );
+    match groups {
+        Response::Success(groups) => Response::Success(
+            groups
+                .into_iter()
+                .filter(|g| g.members.contains(&user))
+                .collect(),
+        ),
+        r => r,
+    }
+}
@@ -1,6 +1,12 @@
 #[macro_use]
 extern crate lazy_static; // used by libnss_*_hooks macros
+use libc::c_char;
 use libnss::{interop::Response, libnss_group_hooks, libnss_passwd_hooks, libnss_shadow_hooks};
+use std::env::VarError;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
 
 mod passwd;
 use passwd::AuthdPasswd;
@@ -15,18 +21,129 @@ use shadow::AuthdShadow;
 use tonic::{Code, Status};
 libnss_shadow_hooks!(authd, AuthdShadow);
 
+mod initgroups;
+pub use initgroups::_nss_authd_initgroups_dyn;
+
 mod logs;
 
+mod fallback;
+
+mod policy;
+
+mod config;
+
+mod snapshot;
+
+mod overrides;
+
+mod names;
+
 mod client;
 
+mod cache;
+
+mod selftest;
+pub use selftest::{self_test, SelfTestReport};
+
+mod api;
+pub use api::{group_by_gid, group_by_name, passwd_by_name, passwd_by_uid, LookupError};
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+/// DEFAULT_CONNECTION_TIMEOUT is the default maximum time to wait for the connection to authd to be
+/// established.
+#[cfg(not(feature = "integration_tests"))]
+const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+#[cfg(feature = "integration_tests")]
+const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DEFAULT_REQUEST_TIMEOUT is the default maximum time to wait for authd to answer a request.
+#[cfg(not(feature = "integration_tests"))]
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+#[cfg(feature = "integration_tests")]
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// DEFAULT_MAX_RESPONSE_SIZE is the default maximum size, in bytes, of a response of authd.
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+/// CONNECTION_TIMEOUT holds the connection timeout, once read from the environment.
+static CONNECTION_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// REQUEST_TIMEOUT holds the request timeout, once read from the environment.
+static REQUEST_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// MAX_RESPONSE_SIZE holds the maximum size of a response of authd, once read from the environment.
+static MAX_RESPONSE_SIZE: OnceLock<usize> = OnceLock::new();
+
+/// connection_timeout returns the maximum time to wait for the connection to authd to be established.
+///
+/// It can be set in milliseconds with AUTHD_NSS_CONNECTION_TIMEOUT_MS, and defaults to
+/// DEFAULT_CONNECTION_TIMEOUT.
+fn connection_timeout() -> Duration {
+    *CONNECTION_TIMEOUT.get_or_init(|| {
+        timeout_from_env(
+            "AUTHD_NSS_CONNECTION_TIMEOUT_MS",
+            DEFAULT_CONNECTION_TIMEOUT,
+        )
+    })
+}
+
+/// request_timeout returns the maximum time to wait for authd to answer a request.
+///
+/// It can be set in milliseconds with AUTHD_NSS_REQUEST_TIMEOUT_MS, and defaults to
+/// DEFAULT_REQUEST_TIMEOUT.
+fn request_timeout() -> Duration {
+    *REQUEST_TIMEOUT
+        .get_or_init(|| timeout_from_env("AUTHD_NSS_REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT))
+}
+
+/// max_response_size returns the maximum size, in bytes, of a response of authd that the module decodes.
+///
+/// Listing the entries of a large directory can exceed the default limit of tonic, in which case the
+/// enumeration fails. The limit can be set with AUTHD_NSS_MAX_RESPONSE_SIZE, and defaults to
+/// DEFAULT_MAX_RESPONSE_SIZE.
+fn max_response_size() -> usize {
+    *MAX_RESPONSE_SIZE.get_or_init(|| {
+        let size = match setting("AUTHD_NSS_MAX_RESPONSE_SIZE").map(|v| v.trim().parse()) {
+            Ok(Ok(size)) if size > 0 => size,
+            Ok(_) => {
+                error!("ignoring invalid AUTHD_NSS_MAX_RESPONSE_SIZE, using the default limit");
+                DEFAULT_MAX_RESPONSE_SIZE
+            }
+            Err(_) => DEFAULT_MAX_RESPONSE_SIZE,
+        };
+        debug!("Responses of authd are limited to {} bytes", size);
+        size
+    })
+}
+
+/// timeout_from_env returns the timeout set in milliseconds in the env variable var, or default if it's
+/// not set or is not a positive number.
+fn timeout_from_env(var: &str, default: Duration) -> Duration {
+    match setting(var).map(|v| v.trim().parse::<u64>()) {
+        Ok(Ok(ms)) if ms > 0 => Duration::from_millis(ms),
+        Ok(_) => {
+            error!("ignoring invalid {}, using the default timeout", var);
+            default
+        }
+        Err(_) => default,
+    }
+}
+
 /// socket_path returns the socket path to connect to the gRPC server.
 ///
-/// It uses the AUTHD_NSS_SOCKET env value if set and the custom_socket feature is enabled,
-/// otherwise it uses the default path.
+/// It uses the AUTHD_NSS_SOCKET env value if set and the socket can be trusted, otherwise it uses the
+/// default path.
 fn socket_path() -> String {
-    #[cfg(feature = "custom_socket")]
     match std::env::var("AUTHD_NSS_SOCKET") {
-        Ok(s) => return s,
+        Ok(s) if is_trusted_socket(&s) => return s,
+        Ok(s) => {
+            error!(
+                "ignoring AUTHD_NSS_SOCKET {}: the socket is not owned by root or the current user, or can be created by others, using default value",
+                s
+            );
+        }
         Err(err) => {
             debug!(
                 "AUTHD_NSS_SOCKET not set or badly configured, using default value: {}",
@@ -34,17 +151,143 @@ fn socket_path() -> String {
             );
         }
     }
-    "/run/authd.sock".to_string()
+    env!("AUTHD_NSS_DEFAULT_SOCKET").to_string()
+}
+
+/// is_trusted_socket returns true if the socket at path, set in the environment, can be used to answer
+/// the lookups of the process.
+///
+/// The module is loaded by privileged processes which may run with the environment of an unprivileged
+/// user, so the socket must be owned by root or by the effective user of the process: a user can then
+/// only point their own processes to a socket they created. If the socket doesn't exist (yet), its
+/// directory must be owned by one of them and must not be writable by others, so that nobody else can
+/// create it. The environment is never trusted by setuid and setgid programs.
+///
+/// Abstract sockets, whose path starts with '@', have no owner nor directory to check: anyone can create
+/// them, so the owner of the process listening on them is checked when connecting instead.
+fn is_trusted_socket(path: &str) -> bool {
+    if is_secure_execution() {
+        return false;
+    }
+    if path.starts_with('@') {
+        return true;
+    }
+    let euid = unsafe { libc::geteuid() };
+    let is_trusted_owner = |uid| uid == 0 || uid == euid;
+
+    match std::fs::metadata(path) {
+        Ok(m) => is_trusted_owner(m.uid()),
+        Err(_) => Path::new(path)
+            .parent()
+            .and_then(|dir| std::fs::metadata(dir).ok())
+            .is_some_and(|m| is_trusted_owner(m.uid()) && m.mode() & 0o022 == 0),
+    }
+}
+
+/// setting returns the value of the setting var, like "AUTHD_NSS_DEBUG", read from the environment or,
+/// if it's not set there, from the config file.
+///
+/// The settings change what the module answers, what it logs and where, so the environment is never
+/// trusted by setuid and setgid programs: they run with the environment of the user who started them,
+/// who could otherwise configure a module running as root. They only use the config file, which is where
+/// the settings that must apply to all the processes, like the denied users, are set.
+fn setting(var: &str) -> Result<String, VarError> {
+    if !is_secure_execution() {
+        if let Ok(value) = std::env::var(var) {
+            return Ok(value);
+        }
+    }
+    config::get(var).ok_or(VarError::NotPresent)
+}
+
+/// is_secure_execution returns true if the process runs a setuid or setgid program, or otherwise gained
+/// privileges when it was started.
+fn is_secure_execution() -> bool {
+    unsafe { libc::getauxval(libc::AT_SECURE) != 0 }
+}
+
+/// FALLBACK_SOCKET_PATHS are the sockets tried, in order, when authd can't be reached on the one returned
+/// by socket_path. This allows authd to move its socket without breaking the installed modules.
+const FALLBACK_SOCKET_PATHS: &[&str] = &["/run/authd/authd.sock"];
+
+/// socket_paths returns the sockets to try to connect to the gRPC server, by order of preference.
+///
+/// The fallback sockets can be overridden with AUTHD_NSS_FALLBACK_SOCKETS (colon-separated) if the
+/// integration_tests feature is enabled.
+fn socket_paths() -> Vec<String> {
+    #[cfg(feature = "integration_tests")]
+    let fallbacks: Vec<String> = match std::env::var("AUTHD_NSS_FALLBACK_SOCKETS") {
+        Ok(s) => s
+            .split(':')
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(_) => FALLBACK_SOCKET_PATHS
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+    };
+    #[cfg(not(feature = "integration_tests"))]
+    let fallbacks: Vec<String> = FALLBACK_SOCKET_PATHS
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+
+    let mut paths = vec![socket_path()];
+    for p in fallbacks {
+        if !paths.contains(&p) {
+            paths.push(p);
+        }
+    }
+    paths
+}
+
+/// RESTART_SENTINEL_PATH is the file which exists while authd is restarting.
+const RESTART_SENTINEL_PATH: &str = "/run/authd.restarting";
+
+/// restart_sentinel_path returns the path of the file which exists while authd is restarting.
+///
+/// It can be overridden with AUTHD_NSS_RESTART_SENTINEL if the integration_tests feature is enabled.
+fn restart_sentinel_path() -> String {
+    #[cfg(feature = "integration_tests")]
+    if let Ok(p) = std::env::var("AUTHD_NSS_RESTART_SENTINEL") {
+        return p;
+    }
+    RESTART_SENTINEL_PATH.to_string()
 }
 
 /// grpc_status_to_nss_response converts a gRPC status to a NSS response.
 fn grpc_status_to_nss_response<T>(status: Status) -> Response<T> {
+    if is_decode_error(&status) {
+        error!(
+            "protocol mismatch: could not decode the response from authd, the NSS module and the daemon versions may differ: {}",
+            status.message()
+        );
+    }
+
     match status.code() {
         Code::NotFound => Response::NotFound,
         _ => Response::Unavail,
     }
 }
 
+/// authd_nss_build_info returns a static string describing how the module was built (version, enabled
+/// features and default socket path), to help diagnosing issues from the installed library.
+#[no_mangle]
+pub extern "C" fn authd_nss_build_info() -> *const c_char {
+    concat!(env!("AUTHD_NSS_BUILD_INFO"), "\0").as_ptr() as *const c_char
+}
+
+/// is_decode_error returns true if the status was generated because a response from authd could not be
+/// decoded, which happens when the module and the daemon don't use the same protocol version.
+fn is_decode_error(status: &Status) -> bool {
+    status.code() == Code::Internal
+        && status
+            .message()
+            .starts_with("failed to decode Protobuf message")
+}
+
+#[cfg(feature = "logging")]
 #[ctor::ctor]
 /// init_logger is a constructor that ensures the logger object initialization only happens once per
 /// library invocation in order to avoid races to the log file.
@@ -55,7 +298,14 @@ fn init_logger() {
 #[cfg(feature = "integration_tests")]
 #[ctor::ctor]
 /// register_local_aad_nss_service_for_tests executes the C API to override the NSS lookup.
+///
+/// The override is only applied when AUTHD_NSS_DB_OVERRIDE is set to 1, so that the same build can be
+/// used to run tests with and without it.
 fn register_local_aad_nss_service_for_tests() {
+    if std::env::var("AUTHD_NSS_DB_OVERRIDE").as_deref() != Ok("1") {
+        return;
+    }
+
     #[link(name = "db_override")]
     extern "C" {
         fn db_override();
@@ -65,3 +315,62 @@ fn register_local_aad_nss_service_for_tests() {
         db_override();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    /// decode_error_status returns the status built by tonic when a response can't be decoded.
+    fn decode_error_status() -> Status {
+        // A field header announcing more bytes than there are.
+        let err = client::authd::PasswdEntry::decode(&[0x0a, 0x05, b'a'][..]).unwrap_err();
+        Status::new(Code::Internal, err.to_string())
+    }
+
+    #[test]
+    fn grpc_status_to_nss_response_maps_the_status_codes() {
+        let tests = [
+            (Status::not_found("user not found"), "NotFound"),
+            (Status::unavailable("authd is down"), "Unavail"),
+            (Status::internal("something went wrong"), "Unavail"),
+            (Status::permission_denied("not allowed"), "Unavail"),
+            (Status::deadline_exceeded("too slow"), "Unavail"),
+            (decode_error_status(), "Unavail"),
+        ];
+
+        for (status, want) in tests {
+            let code = status.code();
+            let got = match grpc_status_to_nss_response::<()>(status) {
+                Response::NotFound => "NotFound",
+                Response::Unavail => "Unavail",
+                _ => "other",
+            };
+            assert_eq!(got, want, "unexpected response for code {:?}", code);
+        }
+    }
+
+    #[test]
+    fn is_trusted_socket_trusts_abstract_sockets() {
+        assert!(
+            is_trusted_socket("@authd-nss-tests"),
+            "abstract socket should be trusted, its owner being checked when connecting"
+        );
+    }
+
+    #[test]
+    fn is_decode_error_only_matches_decode_failures() {
+        assert!(
+            is_decode_error(&decode_error_status()),
+            "response that can't be decoded should be a decode error"
+        );
+        assert!(
+            !is_decode_error(&Status::internal("something went wrong")),
+            "other internal errors should not be decode errors"
+        );
+        assert!(
+            !is_decode_error(&Status::unavailable(decode_error_status().message())),
+            "errors with other codes should not be decode errors"
+        );
+    }
+}
@@ -0,0 +1,427 @@
+use libnss::interop::Response;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use syslog::{Facility, Formatter3164};
+
+use crate::{debug, warn};
+
+/// DEFAULT_LOG_PREFIX is the prefix of the log messages when no other one was configured.
+const DEFAULT_LOG_PREFIX: &str = "authd:";
+
+/// PROCESS_NAME is the name of the process added to the prefix of the log messages, set by init_logger
+/// when AUTHD_NSS_LOG_PID is set.
+static PROCESS_NAME: OnceLock<String> = OnceLock::new();
+
+/// log_prefix returns the prefix to use for the log messages.
+///
+/// The pid is read for each message rather than when the logger is initialized, so that the children
+/// forked after the module was loaded log their own pid.
+pub fn log_prefix() -> Cow<'static, str> {
+    match PROCESS_NAME.get() {
+        Some(name) => Cow::Owned(format!("authd[{}:{}]:", name, std::process::id())),
+        None => Cow::Borrowed(DEFAULT_LOG_PREFIX),
+    }
+}
+
+/// LOOKUP_TARGET is the log target of the lookup events, which are already formatted when using the
+/// JSON output.
+const LOOKUP_TARGET: &str = "authd::lookup";
+
+/// JSON_OUTPUT is set by init_logger when the log messages are formatted as JSON objects.
+static JSON_OUTPUT: OnceLock<bool> = OnceLock::new();
+
+/// REQUEST_COUNTER is used to give a unique id to each lookup performed by the process.
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// LOOKUP_FIELDS holds the fields describing the lookup in progress on the thread.
+    static LOOKUP_FIELDS: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+/// log_fields returns the fields describing the lookup in progress on the thread, like
+/// " op=passwd_by_name name=alice", which are appended to the log messages so that they can be related
+/// to the lookup which produced them. It returns an empty string outside of lookups.
+pub fn log_fields() -> String {
+    LOOKUP_FIELDS.with(|f| f.borrow().clone())
+}
+
+/// lookup_fields returns the fields describing the lookup identified by its kind and key. The name of
+/// the key field comes from the kind, like "uid" for "passwd_by_uid".
+fn lookup_fields(kind: &str, key: &str) -> String {
+    let field = match kind.rsplit_once("_by_") {
+        Some((_, field)) => field,
+        None if kind == "initgroups" => "user",
+        None => return format!(" op={}", kind),
+    };
+
+    // Quote the values which would otherwise be mistaken for other fields.
+    if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        format!(" op={} {}={:?}", kind, field, key)
+    } else {
+        format!(" op={} {}={}", kind, field, key)
+    }
+}
+
+/// lookup runs the lookup f, identified by its kind and key, and logs its outcome and duration.
+///
+/// The messages logged while the lookup is in progress on the thread carry the fields describing it.
+pub fn lookup<T>(kind: &str, key: &str, f: impl FnOnce() -> Response<T>) -> Response<T> {
+    let request_id = format!(
+        "{}-{}",
+        std::process::id(),
+        REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let start = Instant::now();
+    let previous_fields = LOOKUP_FIELDS.with(|f| f.replace(lookup_fields(kind, key)));
+
+    let response = f();
+
+    let outcome = format!("{:?}", response.to_status());
+    let elapsed_ms = start.elapsed().as_millis();
+    if JSON_OUTPUT.get().copied().unwrap_or_default() {
+        log::debug!(
+            target: LOOKUP_TARGET,
+            "{{\"ts\":{},\"level\":\"DEBUG\",\"kind\":{},\"key\":{},\"outcome\":{},\"elapsed_ms\":{},\"request_id\":{}}}",
+            timestamp(),
+            json_string(kind),
+            json_string(key),
+            json_string(&outcome),
+            elapsed_ms,
+            json_string(&request_id),
+        );
+    } else {
+        debug!(
+            "{} {} returned {} in {}ms (request {})",
+            kind, key, outcome, elapsed_ms, request_id
+        );
+    }
+
+    LOOKUP_FIELDS.with(|f| *f.borrow_mut() = previous_fields);
+    response
+}
+
+/// init_logger initialize the global logger with a default level set to info. This function is only
+/// required to be called once and is a no-op on subsequent calls.
+///
+/// The log level can be set to debug by setting the environment variable AUTHD_NSS_DEBUG. The messages
+/// are then printed to stderr if it's set to "stderr", appended to the file at path if it's set to
+/// "file:<path>", and sent to syslog otherwise.
+///
+/// Setting AUTHD_NSS_LOG to json formats each message as a JSON object on its own line, which is
+/// easier to ingest by log pipelines than free-form text.
+///
+/// Setting AUTHD_NSS_LOG_PID makes the log messages prefixed with the name and pid of the process that
+/// loaded the module, which helps correlating messages when many processes use it.
+///
+/// None of these settings are read by setuid and setgid programs, which log to syslog.
+pub fn init_logger() {
+    if log::logger().enabled(&Metadata::builder().build()) {
+        return;
+    }
+
+    if crate::setting("AUTHD_NSS_LOG_PID").is_ok() {
+        let _ = PROCESS_NAME.set(process_name());
+    }
+
+    let json = crate::setting("AUTHD_NSS_LOG").is_ok_and(|v| v == "json");
+    let _ = JSON_OUTPUT.set(json);
+
+    let mut level = LevelFilter::Info;
+    if let Ok(target) = crate::setting("AUTHD_NSS_DEBUG") {
+        level = LevelFilter::Debug;
+        match target {
+            s if s == *"stderr" => init_stderr_logger(level, json),
+            s if s.starts_with("file:") => init_file_logger(&s["file:".len()..], level, json),
+            _ => init_sys_logger(level, json),
+        }
+    } else {
+        init_sys_logger(level, json);
+    }
+
+    debug!("Log level set to {:?}", level);
+}
+
+/// process_name returns the name of the executable of the current process.
+fn process_name() -> String {
+    env::current_exe()
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// init_sys_logger initializes a global log that prints messages to the system logs.
+///
+/// Messages are tagged with a stable identifier, "authd" by default, so that the logs of the module
+/// can be found regardless of the process that loaded it. It can be changed by setting
+/// AUTHD_NSS_SYSLOG_IDENT. The pid of the process is still attached to each message, and its name can
+/// be added with AUTHD_NSS_LOG_PID.
+fn init_sys_logger(log_level: LevelFilter, json: bool) {
+    let formatter = Formatter3164 {
+        facility: Facility::LOG_USER,
+        hostname: None,
+        process: syslog_ident(),
+        pid: std::process::id(),
+    };
+
+    let logger = match syslog_unix(formatter) {
+        Err(err) => {
+            println!("cannot connect to syslog: {err:?}");
+            return;
+        }
+        Ok(l) => l,
+    };
+
+    let mut logger: Box<dyn Log> = Box::new(SyslogLogger {
+        logger: Mutex::new(logger),
+    });
+    if json {
+        logger = Box::new(JsonLogger { output: logger });
+    }
+
+    if let Err(err) = log::set_boxed_logger(logger).map(|()| log::set_max_level(log_level)) {
+        eprintln!("cannot set log level: {err:?}");
+        return;
+    };
+
+    debug!("Log output set to syslog");
+}
+
+/// syslog_unix connects to the local syslog socket.
+///
+/// The socket can be set with AUTHD_NSS_SYSLOG_SOCKET if the integration_tests feature is enabled.
+fn syslog_unix(
+    formatter: Formatter3164,
+) -> syslog::Result<syslog::Logger<syslog::LoggerBackend, Formatter3164>> {
+    #[cfg(feature = "integration_tests")]
+    if let Ok(path) = env::var("AUTHD_NSS_SYSLOG_SOCKET") {
+        return syslog::unix_custom(formatter, path);
+    }
+    syslog::unix(formatter)
+}
+
+/// syslog_ident returns the identifier used to tag the messages sent to syslog.
+///
+/// Setuid programs only use the identifier set in the config file, so that their caller can't tag the
+/// messages logged as root with the name of another program.
+fn syslog_ident() -> String {
+    match crate::setting("AUTHD_NSS_SYSLOG_IDENT") {
+        Ok(ident) if !ident.is_empty() => ident,
+        _ => "authd".to_string(),
+    }
+}
+
+/// init_stderr_logger initializes a global log that prints the messages to stderr.
+fn init_stderr_logger(log_level: LevelFilter, json: bool) {
+    if json {
+        log::set_boxed_logger(Box::new(JsonLogger {
+            output: Box::new(StderrLogger),
+        }))
+        .map(|()| log::set_max_level(log_level))
+        .unwrap();
+    } else {
+        SimpleLogger::new().with_level(log_level).init().unwrap();
+    }
+    debug!("Log output set to stderr");
+}
+
+/// init_file_logger initializes a global log that appends the messages to the file at path, creating it
+/// if needed. If the file can't be opened, the messages are printed to stderr instead.
+fn init_file_logger(path: &str, log_level: LevelFilter, json: bool) {
+    let file = match open_log_file(path) {
+        Ok(f) => f,
+        Err(err) => {
+            init_stderr_logger(log_level, json);
+            warn!("cannot open log file {}, logging to stderr: {}", path, err);
+            return;
+        }
+    };
+
+    let mut logger: Box<dyn Log> = Box::new(FileLogger {
+        file: Mutex::new(file),
+    });
+    if json {
+        logger = Box::new(JsonLogger { output: logger });
+    }
+
+    if let Err(err) = log::set_boxed_logger(logger).map(|()| log::set_max_level(log_level)) {
+        eprintln!("cannot set log level: {err:?}");
+        return;
+    };
+
+    debug!("Log output set to {}", path);
+}
+
+/// open_log_file opens the file at path for appending, creating it if needed, readable by its owner only.
+///
+/// Symbolic links are not followed and the file must be owned by the real user of the process, so that
+/// the module never writes to a file that the user couldn't write to by themselves.
+fn open_log_file(path: &str) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+
+    let uid = unsafe { libc::getuid() };
+    if file.metadata()?.uid() != uid {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("file is not owned by uid {}", uid),
+        ));
+    }
+    Ok(file)
+}
+
+/// SyslogLogger sends the log messages to syslog, tagged with the pid of the process sending them.
+struct SyslogLogger {
+    logger: Mutex<syslog::Logger<syslog::LoggerBackend, Formatter3164>>,
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+        let mut logger = self.logger.lock().unwrap_or_else(|e| e.into_inner());
+        // The pid is read for each message, as the process may have forked since the logger was created.
+        logger.formatter.pid = std::process::id();
+        let _ = match record.level() {
+            Level::Error => logger.err(message),
+            Level::Warn => logger.warning(message),
+            Level::Info => logger.info(message),
+            Level::Debug | Level::Trace => logger.debug(message),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = self
+            .logger
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .backend
+            .flush();
+    }
+}
+
+/// JsonLogger formats the log messages as JSON objects before handing them to its output.
+struct JsonLogger {
+    output: Box<dyn Log>,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.output.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        // Lookup events are already formatted.
+        if record.target() == LOOKUP_TARGET {
+            self.output.log(record);
+            return;
+        }
+
+        self.output.log(
+            &Record::builder()
+                .args(format_args!(
+                    "{{\"ts\":{},\"level\":{},\"kind\":\"message\",\"msg\":{}}}",
+                    timestamp(),
+                    json_string(record.level().as_str()),
+                    json_string(&record.args().to_string()),
+                ))
+                .level(record.level())
+                .target(record.target())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+/// StderrLogger prints the log messages to stderr as they are, one per line.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Trace
+    }
+
+    fn log(&self, record: &Record) {
+        eprintln!("{}", record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/// FileLogger appends the log messages to a file, one per line. JSON messages are written as they are,
+/// the others are prefixed with their time and level.
+struct FileLogger {
+    file: Mutex<File>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Trace
+    }
+
+    fn log(&self, record: &Record) {
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        let line = if JSON_OUTPUT.get().copied().unwrap_or_default() {
+            format!("{}\n", record.args())
+        } else {
+            format!(
+                "{} {:<5} [{}] {}\n",
+                timestamp(),
+                record.level(),
+                record.target(),
+                record.args()
+            )
+        };
+        // A single write per message keeps the lines of concurrent processes from being interleaved.
+        let _ = file.write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().map(|mut f| f.flush());
+    }
+}
+
+/// timestamp returns the current time as seconds since the epoch, with millisecond precision.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
+
+/// json_string returns s as a quoted and escaped JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
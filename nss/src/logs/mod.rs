@@ -1,76 +1,66 @@
-use log::{LevelFilter, Metadata};
-use simple_logger::SimpleLogger;
-use std::env;
-use syslog::{BasicLogger, Facility, Formatter3164};
+#[cfg(not(feature = "logging"))]
+use libnss::interop::Response;
 
+#[cfg(feature = "logging")]
+mod logger;
+#[cfg(feature = "logging")]
+pub use logger::{init_logger, log_fields, log_prefix, lookup};
+
+#[cfg(feature = "logging")]
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {
-        let log_prefix = "authd:";
-        log::debug!("{} {}", log_prefix, format_args!($($arg)*));
+        let log_prefix = $crate::logs::log_prefix();
+        log::debug!("{} {}{}", log_prefix, format_args!($($arg)*), $crate::logs::log_fields());
     }
 }
 
+#[cfg(feature = "logging")]
 #[macro_export]
-macro_rules! error {
+macro_rules! warn {
     ($($arg:tt)*) => {
-        let log_prefix = "authd:";
-        log::error!("{} {}", log_prefix, format_args!($($arg)*));
+        let log_prefix = $crate::logs::log_prefix();
+        log::warn!("{} {}{}", log_prefix, format_args!($($arg)*), $crate::logs::log_fields());
     }
 }
 
-/// init_logger initialize the global logger with a default level set to info. This function is only
-/// required to be called once and is a no-op on subsequent calls.
-///
-/// The log level can be set to debug by setting the environment variable AUTHD_NSS_DEBUG.
-pub fn init_logger() {
-    if log::logger().enabled(&Metadata::builder().build()) {
-        return;
+#[cfg(feature = "logging")]
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        let log_prefix = $crate::logs::log_prefix();
+        log::error!("{} {}{}", log_prefix, format_args!($($arg)*), $crate::logs::log_fields());
     }
+}
 
-    let mut level = LevelFilter::Info;
-    if let Ok(target) = env::var("AUTHD_NSS_DEBUG") {
-        level = LevelFilter::Debug;
-        match target {
-            s if s == *"stderr" => init_stderr_logger(level),
-            _ => init_sys_logger(level),
-        }
-    } else {
-        init_sys_logger(level);
+// Without the logging feature, the messages are compiled out. The arguments are still given to
+// format_args, so that they are type checked and not reported as unused.
+#[cfg(not(feature = "logging"))]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        let _ = format_args!($($arg)*);
     }
-
-    debug!("Log level set to {:?}", level);
 }
 
-/// init_sys_logger initializes a global log that prints messages to the system logs.
-fn init_sys_logger(log_level: LevelFilter) {
-    let formatter = Formatter3164 {
-        facility: Facility::LOG_USER,
-        hostname: None,
-        process: "authd".into(),
-        pid: 0,
-    };
-
-    let logger = match syslog::unix(formatter) {
-        Err(err) => {
-            println!("cannot connect to syslog: {err:?}");
-            return;
-        }
-        Ok(l) => l,
-    };
-
-    if let Err(err) = log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-        .map(|()| log::set_max_level(log_level))
-    {
-        eprintln!("cannot set log level: {err:?}");
-        return;
-    };
+#[cfg(not(feature = "logging"))]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        let _ = format_args!($($arg)*);
+    }
+}
 
-    debug!("Log output set to syslog");
+#[cfg(not(feature = "logging"))]
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        let _ = format_args!($($arg)*);
+    }
 }
 
-/// init_stderr_logger initializes a global log that prints the messages to stderr.
-fn init_stderr_logger(log_level: LevelFilter) {
-    SimpleLogger::new().with_level(log_level).init().unwrap();
-    debug!("Log output set to stderr");
+/// lookup runs the lookup f. Without the logging feature, there is nothing to report about it.
+#[cfg(not(feature = "logging"))]
+pub fn lookup<T>(_kind: &str, _key: &str, f: impl FnOnce() -> Response<T>) -> Response<T> {
+    f()
 }
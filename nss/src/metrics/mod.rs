@@ -0,0 +1,168 @@
+use libc::c_char;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// BUCKET_BOUNDS_MS are the inclusive upper bounds, in milliseconds, of the latency buckets. An extra
+/// bucket counts the latencies above the last bound.
+pub const BUCKET_BOUNDS_MS: [u64; 7] = [1, 5, 10, 50, 100, 500, 1000];
+
+/// BUCKETS is the number of buckets of a histogram.
+const BUCKETS: usize = BUCKET_BOUNDS_MS.len() + 1;
+
+/// ZERO initializes the buckets. Each use of the constant creates a new counter, which is what we want.
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+/// CONNECT_BUCKETS counts the connections to authd by latency.
+static CONNECT_BUCKETS: [AtomicU64; BUCKETS] = [ZERO; BUCKETS];
+
+/// REQUEST_BUCKETS counts the requests sent to authd by latency.
+static REQUEST_BUCKETS: [AtomicU64; BUCKETS] = [ZERO; BUCKETS];
+
+/// CACHE_COUNTS counts the lookups answered with each cache enabled, by cache name.
+static CACHE_COUNTS: Mutex<BTreeMap<&'static str, CacheCounts>> = Mutex::new(BTreeMap::new());
+
+/// Histogram counts latencies in the buckets delimited by BUCKET_BOUNDS_MS.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    pub buckets: [u64; BUCKETS],
+}
+
+impl Histogram {
+    /// count returns the number of latencies recorded in the histogram.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// percentile returns the upper bound, in milliseconds, of the bucket holding the p-th percentile
+    /// of the recorded latencies, or None if nothing was recorded. Latencies above the last bound are
+    /// reported as u64::MAX.
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((p / 100.0) * count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(BUCKET_BOUNDS_MS.get(i).copied().unwrap_or(u64::MAX));
+            }
+        }
+        Some(u64::MAX)
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets: Vec<String> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| match BUCKET_BOUNDS_MS.get(i) {
+                Some(bound) => format!("le{}:{}", bound, n),
+                None => format!("inf:{}", n),
+            })
+            .collect();
+        write!(f, "{}", buckets.join(","))
+    }
+}
+
+/// CacheCounts counts the lookups answered from a cache (hits) and by authd (misses).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Metrics is a snapshot of the latencies and cache usage recorded by the module since it was loaded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// connect holds the time it took to connect to authd.
+    pub connect: Histogram,
+    /// request holds the time it took for authd to answer each request, retries included.
+    pub request: Histogram,
+    /// caches holds the usage of each cache which was enabled, by name.
+    pub caches: BTreeMap<&'static str, CacheCounts>,
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connect_latency={} request_latency={}",
+            self.connect, self.request
+        )?;
+        for (name, c) in &self.caches {
+            write!(f, " cache_{}=hits:{},misses:{}", name, c.hits, c.misses)?;
+        }
+        Ok(())
+    }
+}
+
+/// snapshot returns the latencies and cache usage recorded so far.
+pub fn snapshot() -> Metrics {
+    Metrics {
+        connect: load(&CONNECT_BUCKETS),
+        request: load(&REQUEST_BUCKETS),
+        caches: CACHE_COUNTS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone(),
+    }
+}
+
+/// record_connect records the time it took to connect to authd.
+pub(crate) fn record_connect(elapsed: Duration) {
+    record(&CONNECT_BUCKETS, elapsed);
+}
+
+/// record_request records the time it took for authd to answer a request.
+pub(crate) fn record_request(elapsed: Duration) {
+    record(&REQUEST_BUCKETS, elapsed);
+}
+
+/// record_cache records whether a lookup was answered by the cache named name.
+pub(crate) fn record_cache(name: &'static str, hit: bool) {
+    let mut counts = CACHE_COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+    let c = counts.entry(name).or_default();
+    if hit {
+        c.hits += 1;
+    } else {
+        c.misses += 1;
+    }
+}
+
+/// record increments the bucket of buckets matching elapsed.
+fn record(buckets: &[AtomicU64; BUCKETS], elapsed: Duration) {
+    let ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+    let i = BUCKET_BOUNDS_MS
+        .iter()
+        .position(|bound| ms <= *bound)
+        .unwrap_or(BUCKETS - 1);
+    buckets[i].fetch_add(1, Ordering::Relaxed);
+}
+
+/// load returns the current value of the buckets.
+fn load(buckets: &[AtomicU64; BUCKETS]) -> Histogram {
+    let mut h = Histogram::default();
+    for (n, b) in h.buckets.iter_mut().zip(buckets) {
+        *n = b.load(Ordering::Relaxed);
+    }
+    h
+}
+
+/// authd_nss_metrics returns the latencies and cache usage recorded so far as a string, which must be released with
+/// authd_nss_free_string.
+#[no_mangle]
+pub extern "C" fn authd_nss_metrics() -> *mut c_char {
+    CString::new(snapshot().to_string())
+        .unwrap_or_default()
+        .into_raw()
+}
@@ -0,0 +1,83 @@
+use libnss::interop::Response;
+
+use crate::{debug, error};
+
+/// NameFormat is how the names in the DOMAIN\user form returned by authd are presented to the callers.
+#[derive(PartialEq)]
+enum NameFormat {
+    /// Verbatim keeps the names as returned by authd.
+    Verbatim,
+    /// Upn presents DOMAIN\user as user@DOMAIN.
+    Upn,
+    /// Underscore presents DOMAIN\user as DOMAIN_user.
+    Underscore,
+}
+
+/// name_format returns the format set in AUTHD_NSS_NAME_FORMAT, which can be "upn" or "underscore".
+///
+/// Backslashes in names are mishandled by many tools, like shells and the ones building paths from user
+/// names, so they can be avoided by setting a format. Names are kept verbatim by default.
+fn name_format() -> NameFormat {
+    match crate::setting("AUTHD_NSS_NAME_FORMAT").as_deref() {
+        Ok("upn") => NameFormat::Upn,
+        Ok("underscore") => NameFormat::Underscore,
+        Ok("") | Err(_) => NameFormat::Verbatim,
+        Ok(format) => {
+            error!("ignoring unknown name format {:?}", format);
+            NameFormat::Verbatim
+        }
+    }
+}
+
+/// to_nss returns the name returned by authd in the format presented to the callers.
+///
+/// Only names in the DOMAIN\user form are changed. The case of both parts is preserved, so that the
+/// original name can be restored by to_authd.
+pub fn to_nss(name: String) -> String {
+    let format = name_format();
+    if format == NameFormat::Verbatim {
+        return name;
+    }
+    let (domain, user) = match name.split_once('\\') {
+        Some((domain, user)) if !domain.is_empty() && !user.is_empty() => (domain, user),
+        _ => return name,
+    };
+
+    let mapped = match format {
+        NameFormat::Upn => format!("{}@{}", user, domain),
+        _ => format!("{}_{}", domain, user),
+    };
+    debug!("presenting {} as {}", name, mapped);
+    mapped
+}
+
+/// to_authd returns the DOMAIN\user name matching the name given by a caller, if it has the form set
+/// in AUTHD_NSS_NAME_FORMAT.
+pub fn to_authd(name: &str) -> Option<String> {
+    let (domain, user) = match name_format() {
+        NameFormat::Verbatim => return None,
+        NameFormat::Upn => name.rsplit_once('@').map(|(user, domain)| (domain, user))?,
+        NameFormat::Underscore => name.split_once('_')?,
+    };
+    if domain.is_empty() || user.is_empty() {
+        return None;
+    }
+    Some(format!("{}\\{}", domain, user))
+}
+
+/// by_name performs the lookup f of the entry named name by a caller, with the name known by authd.
+///
+/// A name having the form set in AUTHD_NSS_NAME_FORMAT may also be a plain name, like "john_doe" or
+/// "john@example.com", so it's looked up verbatim if authd doesn't know its DOMAIN\user form.
+pub fn by_name<T>(name: &str, f: impl Fn(String) -> Response<T>) -> Response<T> {
+    let authd_name = match to_authd(name) {
+        Some(n) => n,
+        None => return f(name.to_string()),
+    };
+
+    debug!("looking up {} as {}", name, authd_name);
+    match f(authd_name) {
+        Response::NotFound => f(name.to_string()),
+        r => r,
+    }
+}
@@ -0,0 +1,161 @@
+use libnss::passwd::Passwd;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::{config, debug, error};
+
+/// OVERRIDES_FILE is the file holding the rules overriding the shell and home directory of users.
+const OVERRIDES_FILE: &str = "/etc/authd/nss-overrides.conf";
+
+/// RULES holds the rules of the overrides file, once read.
+static RULES: OnceLock<Vec<Rule>> = OnceLock::new();
+
+/// Rule overrides the shell and/or home directory of the users whose name matches its pattern.
+struct Rule {
+    pattern: String,
+    shell: Option<String>,
+    home: Option<String>,
+}
+
+/// overrides_file returns the path of the rules file.
+///
+/// It uses the AUTHD_NSS_OVERRIDES_FILE env value if set and the integration_tests feature is enabled,
+/// otherwise it uses the default path.
+fn overrides_file() -> PathBuf {
+    #[cfg(feature = "integration_tests")]
+    if let Ok(path) = std::env::var("AUTHD_NSS_OVERRIDES_FILE") {
+        return PathBuf::from(path);
+    }
+    PathBuf::from(OVERRIDES_FILE)
+}
+
+/// apply returns the entries with the shell and home directory overridden by the rules of the overrides
+/// file, if any.
+///
+/// Each line of the file is a glob matching user names ("*" matches any number of characters and "?"
+/// a single one), followed by the values to override, like:
+///
+///   contractor-* shell=/bin/rbash home=/srv/contractors
+///
+/// All the rules matching a user are applied in order, so later rules take precedence. Empty lines and
+/// lines starting with # are ignored. The file is read once per process, and only if it can be trusted.
+pub fn apply(mut entries: Vec<Passwd>) -> Vec<Passwd> {
+    for entry in &mut entries {
+        apply_rules(entry);
+    }
+    entries
+}
+
+/// apply_one returns the entry with the shell and home directory overridden by the rules of the
+/// overrides file, if any.
+pub fn apply_one(mut entry: Passwd) -> Passwd {
+    apply_rules(&mut entry);
+    entry
+}
+
+/// apply_rules overrides the shell and home directory of entry with all the rules matching its name.
+fn apply_rules(entry: &mut Passwd) {
+    let rules = RULES.get_or_init(load_rules);
+    for rule in rules.iter().filter(|r| glob_match(&r.pattern, &entry.name)) {
+        debug!(
+            "overriding passwd entry of {} with rule {}",
+            entry.name, rule.pattern
+        );
+        if let Some(shell) = &rule.shell {
+            entry.shell = shell.clone();
+        }
+        if let Some(home) = &rule.home {
+            entry.dir = home.clone();
+        }
+    }
+}
+
+/// load_rules reads the rules of the overrides file, if it exists and can be trusted. A missing file
+/// means there are no rules, and invalid lines are logged and ignored.
+fn load_rules() -> Vec<Rule> {
+    let path = overrides_file();
+    let content = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(err) => {
+            error!("could not read overrides file {}: {}", path.display(), err);
+            return Vec::new();
+        }
+    };
+    if !config::is_trusted_file(&path) {
+        error!(
+            "ignoring overrides file {}: it must be owned by root and only writable by its owner",
+            path.display()
+        );
+        return Vec::new();
+    }
+
+    let mut rules = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_rule(line) {
+            Some(rule) => rules.push(rule),
+            None => {
+                error!("ignoring invalid rule in {}:{}", path.display(), i + 1);
+            }
+        }
+    }
+    rules
+}
+
+/// parse_rule parses a line of the overrides file.
+fn parse_rule(line: &str) -> Option<Rule> {
+    let mut fields = line.split_whitespace();
+    let mut rule = Rule {
+        pattern: fields.next()?.to_string(),
+        shell: None,
+        home: None,
+    };
+
+    for field in fields {
+        match field.split_once('=')? {
+            ("shell", v) if !v.is_empty() => rule.shell = Some(v.to_string()),
+            ("home", v) if !v.is_empty() => rule.home = Some(v.to_string()),
+            _ => return None,
+        }
+    }
+
+    if rule.shell.is_none() && rule.home.is_none() {
+        return None;
+    }
+    Some(rule)
+}
+
+/// glob_match returns true if name matches pattern, where "*" matches any number of characters and "?"
+/// a single one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+
+    let (mut pi, mut ni) = (0, 0);
+    // Position after the last "*" in the pattern, and the position in name it was matched up to.
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi + 1, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            // Let the last "*" match one more character.
+            pi = sp;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|c| *c == '*')
+}
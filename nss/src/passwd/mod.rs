@@ -1,34 +1,68 @@
-use crate::error;
+use crate::{error, logs};
 use libc::uid_t;
 use libnss::interop::Response;
 use libnss::passwd::{Passwd, PasswdHooks};
-use tokio::runtime::Builder;
-use tonic::Request;
 
 use crate::client::{self, authd};
+use crate::{cache, fallback, names, overrides, policy, snapshot};
 use authd::PasswdEntry;
 
 pub struct AuthdPasswd;
 impl PasswdHooks for AuthdPasswd {
     /// get_all_entries returns all passwd entries.
     fn get_all_entries() -> Response<Vec<Passwd>> {
-        get_all_entries()
+        logs::lookup("passwd_entries", "all", || {
+            if !policy::is_enumeration_enabled() {
+                return Response::Success(vec![]);
+            }
+            let r = snapshot::answer(|s| {
+                Response::Success(s.passwd.into_iter().filter(is_valid_entry).collect())
+            })
+            .unwrap_or_else(|| {
+                cache::cached(&cache::PASSWD_ENTRIES, "passwd_entries", get_all_entries)
+            });
+            policy::limit_enumeration("passwd", r)
+        })
     }
 
     /// get_entry_by_uid returns the passwd entry for the given uid.
     fn get_entry_by_uid(uid: uid_t) -> Response<Passwd> {
-        get_entry_by_uid(uid)
+        logs::lookup("passwd_by_uid", &uid.to_string(), || {
+            if let Some(r) =
+                snapshot::answer(|s| allowed(snapshot::find(s.passwd, |p| p.uid == uid)))
+            {
+                return r;
+            }
+            with_fallback(
+                cache::cached(&cache::PASSWD, &format!("passwd_by_uid:{}", uid), || {
+                    store_fallback(get_entry_by_uid(uid))
+                }),
+                || fallback::passwd_by_uid(uid),
+            )
+        })
     }
 
     /// get_entry_by_name returns the passwd entry for the given name.
     fn get_entry_by_name(name: String) -> Response<Passwd> {
-        get_entry_by_name(name)
+        logs::lookup("passwd_by_name", &name, || {
+            if let Some(r) =
+                snapshot::answer(|s| allowed(snapshot::find(s.passwd, |p| p.name == name)))
+            {
+                return r;
+            }
+            with_fallback(
+                cache::cached(&cache::PASSWD, &format!("passwd_by_name:{}", name), || {
+                    store_fallback(names::by_name(&name, get_entry_by_name))
+                }),
+                || fallback::passwd_by_name(&name),
+            )
+        })
     }
 }
 
 /// get_all_entries connects to the grpc server and asks for all passwd entries.
 fn get_all_entries() -> Response<Vec<Passwd>> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -36,8 +70,8 @@ fn get_all_entries() -> Response<Vec<Passwd>> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -45,20 +79,30 @@ fn get_all_entries() -> Response<Vec<Passwd>> {
             }
         };
 
-        let req = Request::new(authd::Empty {});
-        match client.get_passwd_entries(req).await {
-            Ok(r) => Response::Success(passwd_entries_to_passwds(r.into_inner().entries)),
+        let req = authd::Empty {};
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_passwd_entries(req).await }
+        })
+        .await
+        {
+            Ok(r) => Response::Success(passwd_entries_to_passwds(r.entries)),
             Err(e) => {
                 error!("error when listing passwd: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
 }
 
 /// get_entry_by_uid connects to the grpc server and asks for the passwd entry with the given uid.
 fn get_entry_by_uid(uid: uid_t) -> Response<Passwd> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+    if !policy::is_uid_allowed(uid) {
+        return Response::NotFound;
+    }
+
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -66,8 +110,8 @@ fn get_entry_by_uid(uid: uid_t) -> Response<Passwd> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -75,20 +119,30 @@ fn get_entry_by_uid(uid: uid_t) -> Response<Passwd> {
             }
         };
 
-        let req = Request::new(authd::GetByIdRequest { id: uid });
-        match client.get_passwd_by_uid(req).await {
-            Ok(r) => Response::Success(passwd_entry_to_passwd(r.into_inner())),
+        let req = authd::GetByIdRequest { id: uid };
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_passwd_by_uid(req).await }
+        })
+        .await
+        {
+            Ok(r) => allowed_entry(overrides::apply_one(passwd_entry_to_passwd(r))),
             Err(e) => {
                 error!("error when getting passwd by uid: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
 }
 
 /// get_entry_by_name connects to the grpc server and asks for the passwd entry with the given name.
 fn get_entry_by_name(name: String) -> Response<Passwd> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+    if !policy::is_name_allowed(&name) {
+        return Response::NotFound;
+    }
+
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -96,8 +150,8 @@ fn get_entry_by_name(name: String) -> Response<Passwd> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -105,21 +159,91 @@ fn get_entry_by_name(name: String) -> Response<Passwd> {
             }
         };
 
-        let req = Request::new(authd::GetByNameRequest { name });
-        match client.get_passwd_by_name(req).await {
-            Ok(r) => Response::Success(passwd_entry_to_passwd(r.into_inner())),
+        let req = authd::GetByNameRequest { name };
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_passwd_by_name(req).await }
+        })
+        .await
+        {
+            Ok(r) => allowed_entry(overrides::apply_one(passwd_entry_to_passwd(r))),
             Err(e) => {
                 error!("error when getting passwd by name: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
+}
+
+/// allowed_entry returns the entry, unless its name or uid are not allowed to be answered by the
+/// module or it is invalid, in which case it returns NotFound.
+fn allowed_entry(entry: Passwd) -> Response<Passwd> {
+    if !is_valid_entry(&entry)
+        || !policy::is_name_allowed(&entry.name)
+        || !policy::is_uid_allowed(entry.uid)
+    {
+        return Response::NotFound;
+    }
+    Response::Success(entry)
+}
+
+/// allowed returns the response, unless it holds an entry which is not allowed to be answered.
+fn allowed(response: Response<Passwd>) -> Response<Passwd> {
+    match response {
+        Response::Success(entry) => allowed_entry(entry),
+        r => r,
+    }
+}
+
+/// is_valid_entry returns true if the entry has a name and both its uid and gid are in the allowed
+/// range. Anything else is a bug in authd or the broker and must not be answered.
+fn is_valid_entry(entry: &Passwd) -> bool {
+    if entry.name.is_empty() {
+        error!(
+            "ignoring user with uid {}: authd returned an empty name",
+            entry.uid
+        );
+        return false;
+    }
+    if policy::is_id_in_range(entry.uid) && policy::is_id_in_range(entry.gid) {
+        return true;
+    }
+    error!(
+        "ignoring user {}: uid {} or gid {} is outside of the allowed range",
+        entry.name, entry.uid, entry.gid
+    );
+    false
+}
+
+/// store_fallback stores the entry of a successful response of authd on disk, so that it can be used if
+/// authd becomes unavailable. It must only be given the answers of authd, not the ones from the cache,
+/// which would rewrite the file on every lookup.
+fn store_fallback(response: Response<Passwd>) -> Response<Passwd> {
+    if let Response::Success(entry) = &response {
+        fallback::store_passwd(entry);
+    }
+    response
+}
+
+/// with_fallback answers with the previously stored entry, if there is one, when authd is unavailable.
+///
+/// The stored entry goes through the same checks as the answers of authd, as the settings may have
+/// changed since it was stored.
+fn with_fallback(
+    response: Response<Passwd>,
+    stored: impl FnOnce() -> Option<Passwd>,
+) -> Response<Passwd> {
+    match response {
+        Response::Unavail => stored().map_or(Response::Unavail, allowed_entry),
+        r => r,
+    }
 }
 
 /// passwd_entry_to_passwd converts a PasswdEntry to a libnss::Passwd.
 fn passwd_entry_to_passwd(entry: PasswdEntry) -> Passwd {
     Passwd {
-        name: entry.name,
+        name: names::to_nss(entry.name),
         passwd: entry.passwd,
         uid: entry.uid,
         gid: entry.gid,
@@ -129,7 +253,13 @@ fn passwd_entry_to_passwd(entry: PasswdEntry) -> Passwd {
     }
 }
 
-/// passwd_entries_to_passwds converts a Vec<PasswdEntry> to a Vec<libnss::Passwd>.
+/// passwd_entries_to_passwds converts a Vec<PasswdEntry> to a Vec<libnss::Passwd>, skipping the invalid
+/// entries and applying the overrides.
 fn passwd_entries_to_passwds(entries: Vec<PasswdEntry>) -> Vec<Passwd> {
-    entries.into_iter().map(passwd_entry_to_passwd).collect()
+    let entries = entries
+        .into_iter()
+        .map(passwd_entry_to_passwd)
+        .filter(is_valid_entry)
+        .collect();
+    overrides::apply(entries)
 }
@@ -0,0 +1,135 @@
+use libnss::interop::Response;
+
+use crate::{debug, warn};
+
+/// DEFAULT_ID_RANGE is the range in which authd generates the uids and gids of its users and groups.
+const DEFAULT_ID_RANGE: (u32, u32) = (65536, 99999);
+
+/// is_name_allowed returns true if the module is allowed to answer lookups for the given user name.
+///
+/// Names listed in AUTHD_NSS_DENY_USERS (comma-separated) are never answered, which allows to prevent
+/// authd from shadowing local accounts like root. If AUTHD_NSS_ALLOW_USERS is set, only the names it
+/// lists are answered.
+///
+/// Like the other settings of the policy, they must be set in the config file to apply to setuid
+/// programs, which ignore the environment.
+pub fn is_name_allowed(name: &str) -> bool {
+    if let Ok(denied) = crate::setting("AUTHD_NSS_DENY_USERS") {
+        if denied.split(',').map(str::trim).any(|n| n == name) {
+            debug!("user {} is in AUTHD_NSS_DENY_USERS, not answering", name);
+            return false;
+        }
+    }
+
+    if let Ok(allowed) = crate::setting("AUTHD_NSS_ALLOW_USERS") {
+        if !allowed.split(',').map(str::trim).any(|n| n == name) {
+            debug!(
+                "user {} is not in AUTHD_NSS_ALLOW_USERS, not answering",
+                name
+            );
+            return false;
+        }
+    }
+
+    true
+}
+
+/// is_uid_allowed returns true if the module is allowed to answer lookups for the given uid.
+///
+/// AUTHD_NSS_DENY_UIDS and AUTHD_NSS_ALLOW_UIDS work like their users counterparts, and accept a
+/// comma-separated list of uids or ranges of uids, like "0-999,65534".
+pub fn is_uid_allowed(uid: u32) -> bool {
+    if let Ok(denied) = crate::setting("AUTHD_NSS_DENY_UIDS") {
+        if ids_contain(&denied, uid) {
+            debug!("uid {} is in AUTHD_NSS_DENY_UIDS, not answering", uid);
+            return false;
+        }
+    }
+
+    if let Ok(allowed) = crate::setting("AUTHD_NSS_ALLOW_UIDS") {
+        if !ids_contain(&allowed, uid) {
+            debug!("uid {} is not in AUTHD_NSS_ALLOW_UIDS, not answering", uid);
+            return false;
+        }
+    }
+
+    true
+}
+
+/// is_enumeration_enabled returns true if the module is allowed to list all the entries of a database.
+///
+/// Listing all the users and groups of a large directory is slow and rarely intended, so it is
+/// disabled unless AUTHD_NSS_ENABLE_ENUMERATION is set to 1. When disabled, enumerations (like a bare
+/// "getent passwd") succeed without any authd entry, while lookups by name or id keep working.
+pub fn is_enumeration_enabled() -> bool {
+    if crate::setting("AUTHD_NSS_ENABLE_ENUMERATION").as_deref() == Ok("1") {
+        return true;
+    }
+    debug!("enumeration is disabled, set AUTHD_NSS_ENABLE_ENUMERATION=1 to enable it");
+    false
+}
+
+/// limit_enumeration returns the entries listed by an enumeration of db, truncated to the maximum
+/// number of entries set in AUTHD_NSS_MAX_ENUM_ENTRIES, if any.
+///
+/// The entries of an enumeration are all kept in memory until it ends, so the cap protects the
+/// processes using the module against a huge directory. The entries past the cap are dropped: the
+/// enumeration ends after the first ones as if there were no more, and a warning is logged. Lookups by
+/// name or id are not affected.
+pub fn limit_enumeration<T>(db: &str, r: Response<Vec<T>>) -> Response<Vec<T>> {
+    let max = match crate::setting("AUTHD_NSS_MAX_ENUM_ENTRIES")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+    {
+        Some(max) => max,
+        None => return r,
+    };
+
+    match r {
+        Response::Success(mut entries) if entries.len() > max => {
+            warn!(
+                "{} enumeration truncated to {} of its {} entries, as set by AUTHD_NSS_MAX_ENUM_ENTRIES",
+                db,
+                max,
+                entries.len()
+            );
+            entries.truncate(max);
+            Response::Success(entries)
+        }
+        r => r,
+    }
+}
+
+/// is_id_in_range returns true if the uid or gid returned by authd is in the allowed range.
+///
+/// The range can be set with AUTHD_NSS_UID_RANGE, like "1000000-1999999", and defaults to the one
+/// used by authd to generate ids. It's checked on every answer, so that a misbehaving broker can
+/// never make us return a privileged account, like uid 0. Setuid programs only use the range set in
+/// the config file, so that their caller can't widen it.
+pub fn is_id_in_range(id: u32) -> bool {
+    let (first, last) = crate::setting("AUTHD_NSS_UID_RANGE")
+        .ok()
+        .and_then(|r| parse_id_range(r.trim()))
+        .unwrap_or(DEFAULT_ID_RANGE);
+    id != 0 && (first..=last).contains(&id)
+}
+
+/// ids_contain returns true if id is part of the comma-separated list of ids and ranges of ids.
+///
+/// Invalid elements of the list are ignored.
+fn ids_contain(list: &str, id: u32) -> bool {
+    list.split(',')
+        .filter_map(|e| parse_id_range(e.trim()))
+        .any(|(first, last)| (first..=last).contains(&id))
+}
+
+/// parse_id_range parses a single id ("42") or an inclusive range of ids ("0-999").
+fn parse_id_range(s: &str) -> Option<(u32, u32)> {
+    match s.split_once('-') {
+        Some((first, last)) => Some((first.trim().parse().ok()?, last.trim().parse().ok()?)),
+        None => {
+            let id = s.parse().ok()?;
+            Some((id, id))
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use libc::c_char;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::client::{self, authd};
+
+/// SelfTestReport describes how a full lookup through authd went, to help diagnosing issues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    /// socket_path is the first existing socket among the ones the module tries to connect to.
+    pub socket_path: String,
+    /// precheck_fails is true if the lookups would be rejected before even trying to connect, because
+    /// the socket is missing or the lookups are bypassed for systemd.
+    pub precheck_fails: bool,
+    /// connect_time is the time it took to connect to authd, if the connection succeeded.
+    pub connect_time: Option<Duration>,
+    /// rpc_time is the time it took for authd to answer the lookup, if it was sent.
+    pub rpc_time: Option<Duration>,
+    /// outcome is "success", "not found" or the reason why the lookup failed.
+    pub outcome: String,
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Option<Duration>| d.map_or("-".to_string(), |d| d.as_millis().to_string());
+        write!(
+            f,
+            "socket={} precheck_fails={} connect_ms={} rpc_ms={} outcome={}",
+            self.socket_path,
+            self.precheck_fails,
+            ms(self.connect_time),
+            ms(self.rpc_time),
+            self.outcome
+        )
+    }
+}
+
+/// self_test connects to authd and performs a lookup, reporting what happened at each step.
+///
+/// If username is set, the passwd entry of that user is requested, otherwise all passwd entries are
+/// listed. It's meant for diagnostic tools and is never called by the NSS hooks.
+pub fn self_test(username: Option<&str>) -> SelfTestReport {
+    let socket_paths = crate::socket_paths();
+    let existing = socket_paths.iter().find(|p| !client::is_socket_missing(p));
+    let mut report = SelfTestReport {
+        precheck_fails: client::is_bypassed() || existing.is_none(),
+        socket_path: existing.unwrap_or(&socket_paths[0]).clone(),
+        connect_time: None,
+        rpc_time: None,
+        outcome: String::new(),
+    };
+
+    let rt = match client::runtime() {
+        Ok(rt) => rt,
+        Err(e) => {
+            report.outcome = format!("could not create runtime: {}", e);
+            return report;
+        }
+    };
+
+    rt.block_on(async {
+        let start = Instant::now();
+        let client = match client::new_client().await {
+            Ok(c) => c,
+            Err(e) => {
+                report.outcome = format!("could not connect: {}", e);
+                return;
+            }
+        };
+        report.connect_time = Some(start.elapsed());
+
+        let start = Instant::now();
+        let result = match username {
+            Some(name) => {
+                let req = authd::GetByNameRequest {
+                    name: name.to_string(),
+                };
+                client::call_with_retry(|| {
+                    let mut client = client.clone();
+                    let req = req.clone();
+                    async move { client.get_passwd_by_name(req).await }
+                })
+                .await
+                .map(|_| ())
+            }
+            None => client::call_with_retry(|| {
+                let mut client = client.clone();
+                async move { client.get_passwd_entries(authd::Empty {}).await }
+            })
+            .await
+            .map(|_| ()),
+        };
+        report.rpc_time = Some(start.elapsed());
+
+        report.outcome = match result {
+            Ok(()) => "success".to_string(),
+            Err(e) if e.code() == tonic::Code::NotFound => "not found".to_string(),
+            Err(e) => format!("lookup failed: {}", e.message()),
+        };
+    });
+
+    report
+}
+
+/// authd_nss_self_test runs self_test and returns its report as a string, which must be released with
+/// authd_nss_free_string. username can be NULL to list all entries instead of looking up a user.
+///
+/// # Safety
+///
+/// username must be NULL or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn authd_nss_self_test(username: *const c_char) -> *mut c_char {
+    let username = if username.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(username).to_string_lossy().into_owned())
+    };
+
+    let report = self_test(username.as_deref()).to_string();
+    // The report can't contain NUL bytes, unless authd answered with some in its error message.
+    CString::new(report.replace('\0', ""))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// authd_nss_free_string releases a string returned by the module, like the self test report.
+///
+/// # Safety
+///
+/// s must be NULL or a string returned by the module which was not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn authd_nss_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
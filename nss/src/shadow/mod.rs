@@ -1,10 +1,9 @@
-use crate::error;
+use crate::{error, logs};
 use libnss::interop::Response;
 use libnss::shadow::{Shadow, ShadowHooks};
-use tokio::runtime::Builder;
-use tonic::Request;
 
 use crate::client::{self, authd};
+use crate::{cache, names, policy, snapshot};
 use authd::ShadowEntry;
 
 pub struct AuthdShadow;
@@ -12,18 +11,36 @@ pub struct AuthdShadow;
 impl ShadowHooks for AuthdShadow {
     /// get_all_entries returns all shadow entries.
     fn get_all_entries() -> Response<Vec<Shadow>> {
-        get_all_entries()
+        logs::lookup("shadow_entries", "all", || {
+            if !policy::is_enumeration_enabled() {
+                return Response::Success(vec![]);
+            }
+            let r = snapshot::answer(|s| Response::Success(s.shadow)).unwrap_or_else(|| {
+                cache::cached(&cache::SHADOW_ENTRIES, "shadow_entries", get_all_entries)
+            });
+            policy::limit_enumeration("shadow", r)
+        })
     }
 
     /// get_entry_by_name returns the shadow entry for the given name.
     fn get_entry_by_name(name: String) -> Response<Shadow> {
-        get_entry_by_name(name)
+        logs::lookup("shadow_by_name", &name.clone(), || {
+            if !policy::is_name_allowed(&name) {
+                return Response::NotFound;
+            }
+            if let Some(r) = snapshot::answer(|s| snapshot::find(s.shadow, |sh| sh.name == name)) {
+                return r;
+            }
+            cache::cached(&cache::SHADOW, &format!("shadow_by_name:{}", name), || {
+                names::by_name(&name, get_entry_by_name)
+            })
+        })
     }
 }
 
 /// get_all_entries connects to the grpc server and asks for all shadow entries.
 fn get_all_entries() -> Response<Vec<Shadow>> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -31,8 +48,8 @@ fn get_all_entries() -> Response<Vec<Shadow>> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -40,20 +57,26 @@ fn get_all_entries() -> Response<Vec<Shadow>> {
             }
         };
 
-        let req = Request::new(authd::Empty {});
-        match client.get_shadow_entries(req).await {
-            Ok(r) => Response::Success(shadow_entries_to_shadows(r.into_inner().entries)),
+        let req = authd::Empty {};
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_shadow_entries(req).await }
+        })
+        .await
+        {
+            Ok(r) => Response::Success(shadow_entries_to_shadows(r.entries)),
             Err(e) => {
                 error!("error when listing shadow: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
 }
 
 /// get_entry_by_name connects to the grpc server and asks for the shadow entry with the given name.
 fn get_entry_by_name(name: String) -> Response<Shadow> {
-    let rt = match Builder::new_current_thread().enable_all().build() {
+    let rt = match client::runtime() {
         Ok(rt) => rt,
         Err(e) => {
             error!("could not create runtime for NSS: {}", e);
@@ -61,8 +84,8 @@ fn get_entry_by_name(name: String) -> Response<Shadow> {
         }
     };
 
-    rt.block_on(async {
-        let mut client = match client::new_client().await {
+    rt.block_on(client::cancellable(async {
+        let client = match client::new_client().await {
             Ok(c) => c,
             Err(e) => {
                 error!("could not connect to gRPC server: {}", e);
@@ -70,21 +93,27 @@ fn get_entry_by_name(name: String) -> Response<Shadow> {
             }
         };
 
-        let req = Request::new(authd::GetByNameRequest { name });
-        match client.get_shadow_by_name(req).await {
-            Ok(r) => Response::Success(shadow_entry_to_shadow(r.into_inner())),
+        let req = authd::GetByNameRequest { name };
+        match client::call_with_retry(|| {
+            let mut client = client.clone();
+            let req = req.clone();
+            async move { client.get_shadow_by_name(req).await }
+        })
+        .await
+        {
+            Ok(r) => Response::Success(shadow_entry_to_shadow(r)),
             Err(e) => {
                 error!("error when getting shadow by name: {}", e.message());
                 super::grpc_status_to_nss_response(e)
             }
         }
-    })
+    }))
 }
 
 /// shadow_entries_to_shadows converts a vector of shadow entries to a vector of shadows.
 fn shadow_entry_to_shadow(entry: ShadowEntry) -> Shadow {
     Shadow {
-        name: entry.name,
+        name: names::to_nss(entry.name),
         passwd: entry.passwd,
         last_change: entry.last_change as isize,
         change_min_days: entry.change_min_days as isize,
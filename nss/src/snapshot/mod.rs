@@ -0,0 +1,159 @@
+use libnss::group::Group;
+use libnss::interop::Response;
+use libnss::passwd::Passwd;
+use libnss::shadow::Shadow;
+use std::fs;
+use std::path::Path;
+
+use crate::{config, debug, error};
+
+/// SNAPSHOT_HEADER is the first line of a snapshot file, identifying its format version.
+///
+/// A snapshot is a text file. After the header, each line is an entry made of fields separated by tabs,
+/// the first one being the database of the entry:
+///
+///   passwd <name> <passwd> <uid> <gid> <gecos> <dir> <shell>
+///   group  <name> <passwd> <gid> <comma-separated members>
+///   shadow <name> <passwd> <last change> <min> <max> <warn> <inactive> <expire>
+///
+/// Backslashes, tabs and new lines in the fields are escaped as \\, \t and \n. Empty lines and lines
+/// starting with # are ignored.
+const SNAPSHOT_HEADER: &str = "authd-nss-snapshot v1";
+
+/// Snapshot holds the entries of a snapshot file.
+#[derive(Default)]
+pub struct Snapshot {
+    pub passwd: Vec<Passwd>,
+    pub group: Vec<Group>,
+    pub shadow: Vec<Shadow>,
+}
+
+/// answer returns the response computed by f from the snapshot set in AUTHD_NSS_SNAPSHOT, or Unavail if
+/// the snapshot can't be read.
+///
+/// It returns None if no snapshot is set, in which case the lookup is answered by authd. When one is set,
+/// it is the only source of entries and authd is never contacted, which allows to run without the daemon
+/// on systems where the users and groups don't change anymore.
+///
+/// The snapshot provides the shadow entries and the group memberships, so it must be owned by root and
+/// not writable by others, and setuid programs only use the one set in the config file.
+pub fn answer<T>(f: impl FnOnce(Snapshot) -> Response<T>) -> Option<Response<T>> {
+    let path = crate::setting("AUTHD_NSS_SNAPSHOT").ok()?;
+    let path = Path::new(&path);
+
+    debug!("Answering from snapshot {}", path.display());
+    if path.exists() && !config::is_trusted_file(path) {
+        error!(
+            "ignoring snapshot {}: it must be owned by root and only writable by its owner",
+            path.display()
+        );
+        return Some(Response::Unavail);
+    }
+    match load(path) {
+        Ok(snapshot) => Some(f(snapshot)),
+        Err(err) => {
+            error!("could not read snapshot {}: {}", path.display(), err);
+            Some(Response::Unavail)
+        }
+    }
+}
+
+/// find returns the first of the entries matching the predicate, or NotFound.
+pub fn find<T>(entries: Vec<T>, predicate: impl FnMut(&T) -> bool) -> Response<T> {
+    entries
+        .into_iter()
+        .find(predicate)
+        .map_or(Response::NotFound, Response::Success)
+}
+
+/// load reads and parses the snapshot file at path.
+fn load(path: &Path) -> Result<Snapshot, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut lines = content.lines();
+
+    match lines.next() {
+        Some(SNAPSHOT_HEADER) => {}
+        Some(header) => return Err(format!("unsupported snapshot format {:?}", header)),
+        None => return Err("empty snapshot".to_string()),
+    }
+
+    let mut snapshot = Snapshot::default();
+    for (i, line) in lines.enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Line numbers start at 1 and the header was already consumed.
+        parse_entry(line, &mut snapshot).map_err(|e| format!("line {}: {}", i + 2, e))?;
+    }
+
+    Ok(snapshot)
+}
+
+/// parse_entry parses line and adds its entry to the snapshot.
+fn parse_entry(line: &str, snapshot: &mut Snapshot) -> Result<(), String> {
+    let fields: Vec<String> = line.split('\t').map(unescape).collect();
+    let f: Vec<&str> = fields.iter().map(String::as_str).collect();
+
+    match f.as_slice() {
+        ["passwd", name, passwd, uid, gid, gecos, dir, shell] => snapshot.passwd.push(Passwd {
+            name: name.to_string(),
+            passwd: passwd.to_string(),
+            uid: parse_number(uid)?,
+            gid: parse_number(gid)?,
+            gecos: gecos.to_string(),
+            dir: dir.to_string(),
+            shell: shell.to_string(),
+        }),
+        ["group", name, passwd, gid, members] => snapshot.group.push(Group {
+            name: name.to_string(),
+            passwd: passwd.to_string(),
+            gid: parse_number(gid)?,
+            members: members
+                .split(',')
+                .filter(|m| !m.is_empty())
+                .map(String::from)
+                .collect(),
+        }),
+        ["shadow", name, passwd, last_change, min, max, warn, inactive, expire] => {
+            snapshot.shadow.push(Shadow {
+                name: name.to_string(),
+                passwd: passwd.to_string(),
+                last_change: parse_number(last_change)?,
+                change_min_days: parse_number(min)?,
+                change_max_days: parse_number(max)?,
+                change_warn_days: parse_number(warn)?,
+                change_inactive_days: parse_number(inactive)?,
+                expire_date: parse_number(expire)?,
+                reserved: usize::MAX,
+            })
+        }
+        [db, ..] => return Err(format!("invalid {} entry", db)),
+        [] => return Err("empty entry".to_string()),
+    }
+
+    Ok(())
+}
+
+/// parse_number parses a numeric field of an entry.
+fn parse_number<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid number {:?}", s))
+}
+
+/// unescape returns the field with its escaped backslashes, tabs and new lines restored.
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}